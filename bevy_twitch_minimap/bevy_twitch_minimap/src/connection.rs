@@ -0,0 +1,359 @@
+//! State of the connection to the server, and why it failed.
+
+use std::fmt;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use twitch_minimap_protocol::CloseReason;
+
+use crate::transport;
+
+/// Where the connection to the server is at.
+///
+/// Changes are also sent as [`ConnectionChanged`] events.
+#[derive(Resource, Clone, Debug, Default, PartialEq, Eq)]
+pub enum MinimapConnectionState {
+    /// Not connected, nothing was tried yet.
+    #[default]
+    Disconnected,
+    /// Asking the server for a lobby.
+    CreatingLobby,
+    /// Opening the websocket to the lobby, or waiting to resume it after the connection dropped.
+    ///
+    /// Every attempt to resume is announced with a [`Reconnecting`] event.
+    Connecting,
+    /// Connected, viewers see the minimap.
+    Connected,
+    /// The connection failed and will not be tried again.
+    Failed(ConnectionError),
+}
+
+impl MinimapConnectionState {
+    /// Whether viewers currently get what the game sends.
+    pub fn is_connected(&self) -> bool {
+        *self == Self::Connected
+    }
+}
+
+/// The [`MinimapConnectionState`] changed.
+#[derive(Event, Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionChanged {
+    /// The new state.
+    pub state: MinimapConnectionState,
+}
+
+/// The connection dropped or could not be opened, and is tried again after `delay`.
+#[derive(Event, Clone, Debug, PartialEq, Eq)]
+pub struct Reconnecting {
+    /// Number of attempts in a row, starting at 1.
+    pub attempt: u32,
+    /// How long until the attempt.
+    pub delay: Duration,
+    /// Why the last attempt failed.
+    pub reason: ConnectionError,
+}
+
+/// How long to wait before trying to reconnect.
+///
+/// The delay starts at `initial` and is multiplied by `multiplier` after every failed attempt, up
+/// to `max`. While the lobby still exists the plugin resumes it with its key, so viewers stay
+/// connected; the server keeps a lobby for a while after the game disconnects. Once it is gone a
+/// new lobby is created.
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub struct Backoff {
+    /// Delay before the first attempt.
+    pub initial: Duration,
+    /// Longest delay between attempts.
+    pub max: Duration,
+    /// How much the delay grows after every failed attempt.
+    pub multiplier: f32,
+    /// Share of the delay that is added or removed at random, from 0 to 1.
+    ///
+    /// This keeps games that lost their connection at the same time, e.g. because the server
+    /// restarted, from all reconnecting at once.
+    pub jitter: f32,
+    /// Give up after this many failed attempts in a row, `None` to keep trying.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: Some(20),
+        }
+    }
+}
+
+impl Backoff {
+    /// Never reconnect, the first error fails the connection.
+    pub fn never() -> Self {
+        Self {
+            max_attempts: Some(0),
+            ..Self::default()
+        }
+    }
+
+    /// Delay before `attempt`, starting at 1.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let exponent = i32::try_from(attempt.saturating_sub(1)).unwrap_or(i32::MAX);
+        let delay = self.initial.as_secs_f32() * self.multiplier.max(1.0).powi(exponent);
+        let delay = delay.min(self.max.as_secs_f32());
+        let jitter = self.jitter.clamp(0.0, 1.0) * (transport::random() * 2.0 - 1.0);
+        Duration::from_secs_f32(delay * (1.0 + jitter))
+    }
+}
+
+/// Why connecting to the server failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionError {
+    /// The server could not be reached, e.g. because the host does not resolve.
+    Request(String),
+    /// The server refused the lobby or the connection to it, or closed the connection.
+    Server(ServerError),
+    /// The server answered with something the plugin does not understand.
+    InvalidResponse(String),
+    /// The websocket to the lobby could not be opened.
+    WebSocket(String),
+    /// The connection to the lobby dropped.
+    Closed,
+}
+
+impl ConnectionError {
+    /// Whether trying again may help, e.g. because the network or the server is down for a moment.
+    pub fn is_temporary(&self) -> bool {
+        match self {
+            Self::Request(_) | Self::WebSocket(_) | Self::Closed => true,
+            Self::Server(err) => err.is_temporary(),
+            Self::InvalidResponse(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Request(err) => write!(f, "could not reach the server: {err}"),
+            Self::Server(err) => err.fmt(f),
+            Self::InvalidResponse(err) => write!(f, "unexpected answer from the server: {err}"),
+            Self::WebSocket(err) => write!(f, "could not connect to the lobby: {err}"),
+            Self::Closed => f.write_str("the connection to the lobby dropped"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+/// An error the server answered with, holding the reason it gave.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerError {
+    /// The channel already has a lobby, e.g. because another instance of the game is running.
+    LobbyAlreadyExists(String),
+    /// The lobby does not exist.
+    NotFound(String),
+    /// The key or token is wrong.
+    NotAllowed(String),
+    /// A key or token is missing, or has expired.
+    Unauthorized(String),
+    /// The server closed the lobby, e.g. because the streamer or an admin closed it. A new lobby
+    /// is created when trying again.
+    Closed(String),
+    /// Another connection, e.g. another instance of the game, took over the lobby.
+    TakenOver(String),
+    /// The key of the lobby was rotated by someone else.
+    KeyRotated(String),
+    /// The server closed the connection for any other reason, e.g. because the game speaks
+    /// another protocol than the lobby was created with.
+    Refused(String),
+    /// Any other status.
+    Other {
+        /// Http status code.
+        status: u16,
+        /// Body of the response.
+        reason: String,
+    },
+}
+
+impl ServerError {
+    /// Read an error response with `status` and `body`.
+    pub(crate) fn new(status: u16, body: String) -> Self {
+        match status {
+            409 => Self::LobbyAlreadyExists(body),
+            404 => Self::NotFound(body),
+            403 => Self::NotAllowed(body),
+            401 => Self::Unauthorized(body),
+            status => Self::Other {
+                status,
+                reason: body,
+            },
+        }
+    }
+
+    /// Read a close frame of the server with `code` and `reason`.
+    pub(crate) fn closed(code: u16, reason: String) -> Self {
+        match CloseReason::from_code(code) {
+            Some(CloseReason::LobbyClosed) => Self::Closed(reason),
+            Some(CloseReason::TakenOver) => Self::TakenOver(reason),
+            Some(CloseReason::KeyRotated) => Self::KeyRotated(reason),
+            None => Self::Refused(reason),
+        }
+    }
+
+    /// Whether trying again may help.
+    ///
+    /// A lobby that already exists may be the one of this game that the server has not noticed
+    /// is gone yet, it is removed once it times out. A closed lobby is replaced by a new one.
+    pub fn is_temporary(&self) -> bool {
+        match self {
+            Self::LobbyAlreadyExists(_) | Self::Closed(_) => true,
+            Self::NotFound(_)
+            | Self::NotAllowed(_)
+            | Self::Unauthorized(_)
+            | Self::TakenOver(_)
+            | Self::KeyRotated(_)
+            | Self::Refused(_) => false,
+            Self::Other { status, .. } => *status >= 500,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LobbyAlreadyExists(reason)
+            | Self::NotFound(reason)
+            | Self::NotAllowed(reason)
+            | Self::Unauthorized(reason) => f.write_str(reason),
+            Self::Closed(reason) => write!(f, "the server closed the lobby: {reason}"),
+            Self::TakenOver(reason) | Self::KeyRotated(reason) | Self::Refused(reason) => {
+                write!(f, "the server closed the connection: {reason}")
+            }
+            Self::Other { status, reason } => write!(f, "server answered {status}: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steady() -> Backoff {
+        Backoff {
+            jitter: 0.0,
+            ..Backoff::default()
+        }
+    }
+
+    #[test]
+    fn delay_grows_up_to_max() {
+        let backoff = steady();
+        let delays: Vec<_> = (1..=7)
+            .map(|attempt| backoff.delay(attempt).as_secs())
+            .collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30]);
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn delay_jitter_stays_in_bounds() {
+        let backoff = Backoff {
+            jitter: 0.2,
+            ..steady()
+        };
+        for _ in 0..100 {
+            let delay = backoff.delay(3).as_secs_f32();
+            assert!((3.2..=4.8).contains(&delay), "{delay}");
+        }
+
+        let wild = Backoff {
+            jitter: 5.0,
+            ..steady()
+        };
+        for _ in 0..100 {
+            assert!(wild.delay(3) <= Duration::from_secs(8));
+        }
+    }
+
+    #[test]
+    fn delay_never_shrinks() {
+        let backoff = Backoff {
+            multiplier: 0.5,
+            ..steady()
+        };
+        assert_eq!(backoff.delay(1), Duration::from_secs(1));
+        assert_eq!(backoff.delay(5), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn never() {
+        let backoff = Backoff::never();
+        assert_eq!(backoff.max_attempts, Some(0));
+        assert_eq!(backoff.initial, Backoff::default().initial);
+    }
+
+    #[test]
+    fn server_error_status() {
+        let reason = || String::from("reason");
+        assert_eq!(
+            ServerError::new(409, reason()),
+            ServerError::LobbyAlreadyExists(reason())
+        );
+        assert_eq!(
+            ServerError::new(404, reason()),
+            ServerError::NotFound(reason())
+        );
+        assert_eq!(
+            ServerError::new(403, reason()),
+            ServerError::NotAllowed(reason())
+        );
+        assert_eq!(
+            ServerError::new(401, reason()),
+            ServerError::Unauthorized(reason())
+        );
+        assert_eq!(
+            ServerError::new(502, reason()),
+            ServerError::Other {
+                status: 502,
+                reason: reason()
+            }
+        );
+    }
+
+    #[test]
+    fn server_closes() {
+        let closed = |code| {
+            let err = ServerError::closed(code, "reason".into());
+            (err.is_temporary(), err)
+        };
+        let reason = || String::from("reason");
+        assert_eq!(closed(1001), (true, ServerError::Closed(reason())));
+        assert_eq!(closed(4001), (false, ServerError::TakenOver(reason())));
+        assert_eq!(closed(4002), (false, ServerError::KeyRotated(reason())));
+        assert_eq!(closed(1002), (false, ServerError::Refused(reason())));
+    }
+
+    #[test]
+    fn temporary() {
+        let reason = || String::from("reason");
+        let server = |status| ConnectionError::Server(ServerError::new(status, reason()));
+
+        assert!(ConnectionError::Request(reason()).is_temporary());
+        assert!(ConnectionError::WebSocket(reason()).is_temporary());
+        assert!(ConnectionError::Closed.is_temporary());
+        assert!(server(409).is_temporary());
+        assert!(server(500).is_temporary());
+        assert!(server(503).is_temporary());
+
+        assert!(ConnectionError::Server(ServerError::Closed(reason())).is_temporary());
+
+        assert!(!ConnectionError::InvalidResponse(reason()).is_temporary());
+        for status in [400, 401, 403, 404, 422] {
+            assert!(!server(status).is_temporary(), "{status}");
+        }
+    }
+}
@@ -1,75 +1,131 @@
-use std::net::TcpStream;
-use std::sync::{mpsc, Mutex};
-use std::thread;
+use std::collections::HashMap;
 use std::time::Duration;
 
+use async_channel::{Receiver, Sender};
 use bevy::prelude::*;
+use bevy::tasks::IoTaskPool;
+use futures_lite::future;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use websocket::sync::{self as ws, Reader, Writer};
-use websocket::ws::dataframe::DataFrame;
-use websocket::OwnedMessage;
+use transport::{Message, WebSocket};
+use twitch_minimap_protocol::{url, Click, ClientData, Feature, Presence, Protocol, Version};
+use twitch_minimap_protocol::{StreamerLogin, ViewerMessage};
 
-const HOST: &str = "websocket.matissetec.dev";
+pub use connection::{
+    Backoff, ConnectionChanged, ConnectionError, MinimapConnectionState, Reconnecting, ServerError,
+};
+pub use twitch_minimap_protocol::{
+    Reset, Role, SendToViewer, ServerData, ServerEvent, Unit, Viewer,
+};
 
-/// Represents the unit data for the minimap.
-///
-/// You should rarely need to construct or interact with this yourself
-#[derive(Serialize, Debug, Clone)]
-pub struct Unit {
-    pub id: u32,
-    pub kind: String,
-    pub x: f32,
-    pub y: f32,
-}
+mod connection;
+mod transport;
 
-/// Represents possible events to be sent to the server.
-///
-/// The only one you should want to construct yourself is `Reset`, as the other variants are sent
-/// automatically by the `TwitchMinimapPlugin`
-#[derive(Serialize, Debug, Clone)]
-#[serde(rename_all = "lowercase")]
-pub enum ServerData {
-    Css(String),
-    Reset(()),
-    #[serde(untagged)]
-    Units(Vec<Unit>),
-}
+const HOST: &str = "websocket.matissetec.dev";
 
-/// A server event is the data sent to the server, you should rarely need to construct this
-/// yourself.
-#[derive(Serialize, Event, Debug, Clone)]
-pub struct ServerEvent {
-    pub data: ServerData,
-}
+/// Optional messages the plugin sends, declared when creating the lobby so viewers know what to
+/// expect.
+const FEATURES: [Feature; 3] = [Feature::Css, Feature::Reset, Feature::Targeted];
 
 /// This is an event that is triggered when a user clicks on the minimap
 /// The `x` and `y` values are normalized between 0-1.
-#[derive(Deserialize, Event, Clone, Debug)]
-#[serde(rename_all = "camelCase")]
+#[derive(Event, Clone, Debug)]
 pub struct ClickEvent {
     pub x: f32,
     pub y: f32,
-    pub user_id: String,
     pub bubble_color: String,
     pub bubble_size: f32,
+    /// The viewer who clicked.
+    pub viewer: Viewer,
 }
 
 /// A event from a client.
 ///
 /// You can also listen to the variants directly.
 #[derive(Deserialize, Event, Debug)]
-#[serde(untagged)]
+#[serde(from = "ViewerMessage")]
 pub enum ClientEvent {
     Click(ClickEvent),
 }
 
+impl From<ViewerMessage> for ClientEvent {
+    fn from(message: ViewerMessage) -> Self {
+        let viewer = message.viewer;
+        match message.data {
+            ClientData::Click(Click {
+                x,
+                y,
+                bubble_color,
+                bubble_size,
+                ..
+            }) => ClientEvent::Click(ClickEvent {
+                x,
+                y,
+                bubble_color,
+                bubble_size,
+                viewer,
+            }),
+        }
+    }
+}
+
+/// A viewer opened the extension.
+#[derive(Event, Clone, Debug)]
+pub struct ViewerJoined {
+    pub viewer: Viewer,
+}
+
+/// A viewer closed the extension.
+#[derive(Event, Clone, Debug)]
+pub struct ViewerLeft {
+    pub viewer: Viewer,
+}
+
+/// Anything the server sends to the game.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Incoming {
+    Presence(Presence),
+    Client(ClientEvent),
+}
+
+/// The viewers currently connected to the lobby.
+///
+/// A viewer with multiple tabs open is in here once per connection.
+#[derive(Resource, Default, Debug)]
+pub struct Viewers {
+    connections: HashMap<String, Viewer>,
+}
+
+impl Viewers {
+    /// The number of open connections.
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Whether nobody is watching.
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// The viewer of a connection.
+    pub fn get(&self, connection_id: &str) -> Option<&Viewer> {
+        self.connections.get(connection_id)
+    }
+
+    /// Every connected viewer.
+    pub fn iter(&self) -> impl Iterator<Item = &Viewer> {
+        self.connections.values()
+    }
+}
+
 /// This is the main component you will interact with.
 /// The plugin will automatically transmit the data of all entities marked with this component to
 /// the server.
 #[derive(Component, Debug)]
 pub struct OnMinimap {
     /// This is added as a css class to the unit divs
-    /// As such it is useful for reusing css without needing to duplicate it across `extra_css` 
+    /// As such it is useful for reusing css without needing to duplicate it across `extra_css`
     pub kind: String,
     /// The color the entity will have on the map
     pub color: Color,
@@ -88,6 +144,9 @@ impl Default for OnMinimap {
 }
 
 /// Emit this event once to trigger the connection to the server.
+///
+/// Emitting it again while connected leaves the current lobby first, like [`Disconnect`], and
+/// connects once the server closed it.
 #[derive(Event, Clone, Debug)]
 pub struct Connect {
     pub host: String,
@@ -104,6 +163,13 @@ impl Connect {
     }
 }
 
+/// Emit this event to leave the lobby.
+///
+/// The lobby is closed, so viewers are told the game left, and the connection is shut down.
+/// Send [`Connect`] afterwards to connect again, to the same or another channel.
+#[derive(Event, Clone, Debug, Default)]
+pub struct Disconnect;
+
 /// Contains information on the world space which is used to normalize entity positions.
 #[derive(Resource, Clone)]
 pub struct WorldInfo {
@@ -126,10 +192,61 @@ impl UpdateTimer {
     }
 }
 
+/// How messages to and from the server are encoded.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// JSON text, the default.
+    #[default]
+    Json,
+    /// MessagePack, which is smaller than JSON, especially for many units.
+    ///
+    /// Viewers whose extension only reads JSON still get JSON, the server converts it for them.
+    MessagePack,
+}
+
+impl Encoding {
+    /// Query parameter telling the server which encoding to send.
+    fn query(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+        }
+    }
+
+    fn encode(self, value: &impl Serialize) -> Option<Message> {
+        match self {
+            Encoding::Json => serde_json::to_string(value).ok().map(Message::Text),
+            Encoding::MessagePack => rmp_serde::to_vec_named(value).ok().map(Message::Binary),
+        }
+    }
+}
+
+/// Read a message from the server, the frame type tells how it is encoded.
+fn decode<T: DeserializeOwned>(message: &Message) -> Option<T> {
+    match message {
+        Message::Text(text) => serde_json::from_str(text).ok(),
+        Message::Binary(bytes) => rmp_serde::from_slice(bytes).ok(),
+    }
+}
+
+/// What the connection task tells the game.
+enum Update {
+    /// A message from the server.
+    Message(Incoming),
+    /// The connection changed its state.
+    State(MinimapConnectionState),
+    /// The connection is tried again.
+    Reconnecting(Reconnecting),
+}
+
+/// The connection task, it shuts down once this is removed.
 #[derive(Resource)]
 struct Channels {
-    client_events: Mutex<std::sync::mpsc::Receiver<ClientEvent>>,
-    server_events: std::sync::mpsc::Sender<ServerEvent>,
+    updates: Receiver<Update>,
+    /// Encoded messages for the server.
+    server_events: Sender<Message>,
+    /// Never sent on, dropping it wakes the connection task while it waits to reconnect.
+    _stop: Sender<()>,
 }
 
 /// The main plugin.
@@ -143,23 +260,42 @@ pub struct TwitchMinimapPlugin {
     /// This is mainly recommended for testing as usually you would want to have the user enter the
     /// channel information in a UI.
     pub auto_connect: Option<Connect>,
+    /// How messages are encoded, sent along when connecting so the server can convert them for
+    /// viewers that need JSON.
+    pub encoding: Encoding,
+    /// How to reconnect when the connection drops or cannot be opened.
+    pub reconnect: Backoff,
 }
 
 impl Plugin for TwitchMinimapPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<ServerEvent>()
+            .add_event::<SendToViewer>()
             .add_event::<ClickEvent>()
             .add_event::<ClientEvent>()
+            .add_event::<ViewerJoined>()
+            .add_event::<ViewerLeft>()
             .add_event::<Connect>()
+            .add_event::<Disconnect>()
+            .add_event::<ConnectionChanged>()
+            .add_event::<Reconnecting>()
             .insert_resource(self.world.clone())
+            .insert_resource(self.encoding)
+            .insert_resource(self.reconnect.clone())
             .insert_resource(UpdateTimer::new(self.send_interval))
             .init_resource::<ExtraCss>()
+            .init_resource::<Viewers>()
+            .init_resource::<MinimapConnectionState>()
             .add_systems(
                 Update,
                 (
                     spread_client_event,
-                    handle_connect_event,
-                    (translate_client_event, translate_server_event)
+                    (handle_disconnect_event, handle_connect_event).chain(),
+                    (
+                        translate_client_event,
+                        translate_server_event,
+                        translate_send_to_viewer,
+                    )
                         .run_if(resource_exists::<Channels>),
                     update_unit_positions,
                     update_css,
@@ -174,85 +310,332 @@ impl Plugin for TwitchMinimapPlugin {
     }
 }
 
-fn handle_connect_event(mut commands: Commands, mut connect: EventReader<Connect>) {
+fn handle_connect_event(
+    mut commands: Commands,
+    mut connect: EventReader<Connect>,
+    encoding: Res<Encoding>,
+    backoff: Res<Backoff>,
+    mut last_task: Local<Option<Receiver<()>>>,
+) {
     for connect in connect.read() {
         let connect = connect.clone();
+        let encoding = *encoding;
+        let backoff = backoff.clone();
 
-        let (client_sender, client_recv) = mpsc::channel();
-        let (server_sender, server_recv) = mpsc::channel();
+        let (update_sender, update_recv) = async_channel::unbounded();
+        let (server_sender, server_recv) = async_channel::unbounded();
+        let (stop_sender, stop_recv) = async_channel::bounded(1);
+        // Never sent on, closed once the task is done
+        let (done, done_recv) = async_channel::bounded::<()>(1);
+        let previous = last_task.replace(done_recv);
 
+        // Replacing the channels of a previous connection shuts it down
         let channels = Channels {
-            client_events: Mutex::new(client_recv),
+            updates: update_recv,
             server_events: server_sender,
+            _stop: stop_sender,
         };
         commands.insert_resource(channels);
+        // The roster of a previous lobby does not apply anymore
+        commands.insert_resource(Viewers::default());
+
+        IoTaskPool::get()
+            .spawn(async move {
+                // The previous task closes its lobby first, the server allows one per channel
+                if let Some(previous) = previous {
+                    let _ = previous.recv().await;
+                }
+                establish_connection(
+                    connect,
+                    encoding,
+                    backoff,
+                    update_sender,
+                    server_recv,
+                    stop_recv,
+                )
+                .await;
+                drop(done);
+            })
+            .detach();
+    }
+}
 
-        thread::spawn(move || {
-            establish_connection(connect, client_sender, server_recv);
+fn handle_disconnect_event(
+    mut commands: Commands,
+    mut disconnect: EventReader<Disconnect>,
+    mut state: ResMut<MinimapConnectionState>,
+    mut changed: EventWriter<ConnectionChanged>,
+) {
+    if disconnect.read().count() == 0 {
+        return;
+    }
+    commands.remove_resource::<Channels>();
+    commands.insert_resource(Viewers::default());
+    if *state != MinimapConnectionState::Disconnected {
+        *state = MinimapConnectionState::Disconnected;
+        changed.send(ConnectionChanged {
+            state: MinimapConnectionState::Disconnected,
         });
     }
 }
 
-fn establish_connection(
+/// Connect and keep the connection up, until the game drops [`Channels`].
+///
+/// `stop` is closed once the game dropped them.
+async fn establish_connection(
     connect: Connect,
-    client_events: mpsc::Sender<ClientEvent>,
-    server_events: mpsc::Receiver<ServerEvent>,
+    encoding: Encoding,
+    backoff: Backoff,
+    updates: Sender<Update>,
+    server_events: Receiver<Message>,
+    stop: Receiver<()>,
 ) {
+    let state = |state| {
+        let _ = updates.try_send(Update::State(state));
+    };
+
+    let mut login = None;
+    let mut attempt = 0;
+    loop {
+        let reason = match open_connection(&connect, encoding, &mut login, &updates).await {
+            Ok(socket) => {
+                attempt = 0;
+                match forward_messages(socket, &updates, &server_events).await {
+                    Disconnected::Server(reason) => reason,
+                    Disconnected::Game => break,
+                }
+            }
+            Err(err) => err,
+        };
+        // The lobby is gone, so the next attempt creates a new one instead of resuming
+        if matches!(reason, ConnectionError::Server(ServerError::Closed(_))) {
+            login = None;
+        }
+
+        attempt += 1;
+        if !reason.is_temporary() || backoff.max_attempts.is_some_and(|max| attempt > max) {
+            state(MinimapConnectionState::Failed(reason));
+            break;
+        }
+        let delay = backoff.delay(attempt);
+        state(MinimapConnectionState::Connecting);
+        let _ = updates.try_send(Update::Reconnecting(Reconnecting {
+            attempt,
+            delay,
+            reason,
+        }));
+        let stopped = future::or(
+            async {
+                let _ = stop.recv().await;
+                true
+            },
+            async {
+                transport::sleep(delay).await;
+                false
+            },
+        );
+        if stopped.await {
+            break;
+        }
+    }
+
+    if let Some(login) = login {
+        close_lobby(&connect, &login).await;
+    }
+}
+
+/// Connect to the lobby of `login`, or to a new lobby if there is none.
+///
+/// Resuming with the same key keeps viewers connected, `login` is cleared once the server dropped
+/// the lobby.
+async fn open_connection(
+    connect: &Connect,
+    encoding: Encoding,
+    login: &mut Option<StreamerLogin>,
+    updates: &Sender<Update>,
+) -> Result<WebSocket, ConnectionError> {
+    let state = |state| {
+        let _ = updates.try_send(Update::State(state));
+    };
+
+    if login.is_some() && !lobby_exists(connect).await? {
+        *login = None;
+    }
+    let login = match login {
+        Some(login) => login,
+        None => {
+            state(MinimapConnectionState::CreatingLobby);
+            login.insert(create_lobby(connect).await?)
+        }
+    };
+
+    state(MinimapConnectionState::Connecting);
+    let socket =
+        WebSocket::connect(&format!("{}&encoding={}", login.url, encoding.query())).await?;
+    state(MinimapConnectionState::Connected);
+    Ok(socket)
+}
+
+/// Ask the server for a lobby for the channel.
+async fn create_lobby(connect: &Connect) -> Result<StreamerLogin, ConnectionError> {
+    let protocol = Protocol {
+        version: Version::LATEST,
+        features: FEATURES.to_vec(),
+    };
     let url = format!(
-        "https://{}/lobby/new?user={}",
-        connect.host, connect.channel
+        "https://{}{}",
+        connect.host,
+        url::new_lobby(&connect.channel, &protocol)
     );
-    let key = reqwest::blocking::Client::new()
-        .post(url)
-        .send()
-        .unwrap()
-        .text()
-        .unwrap();
+    let (status, body) = transport::request("POST", &url).await?;
+    if !(200..300).contains(&status) {
+        return Err(ConnectionError::Server(ServerError::new(status, body)));
+    }
+    serde_json::from_str(&body).map_err(|err| ConnectionError::InvalidResponse(err.to_string()))
+}
 
+/// Whether the server still keeps the lobby of the channel.
+async fn lobby_exists(connect: &Connect) -> Result<bool, ConnectionError> {
+    let url = format!("https://{}{}", connect.host, url::status(&connect.channel));
+    match transport::request("GET", &url).await? {
+        (200..=299, _) => Ok(true),
+        (404, _) => Ok(false),
+        (status, body) => Err(ConnectionError::Server(ServerError::new(status, body))),
+    }
+}
+
+/// Close the lobby, so viewers are told the game left and the channel can get a new lobby right
+/// away.
+async fn close_lobby(connect: &Connect, login: &StreamerLogin) {
     let url = format!(
-        "ws://{}/lobby/connect/streamer?user={}&key={}",
-        connect.host, connect.channel, key
+        "https://{}{}",
+        connect.host,
+        url::close(&connect.channel, &login.key)
     );
-    let client = ws::client::ClientBuilder::new(&url)
-        .unwrap()
-        .connect_insecure()
-        .unwrap();
+    // The lobby times out by itself if this fails
+    let _ = transport::request("DELETE", &url).await;
+}
 
-    let (reader, writer) = client.split().unwrap();
+/// Which side of the connection went away.
+enum Disconnected {
+    /// The websocket closed, for the given reason.
+    Server(ConnectionError),
+    /// The game disconnected or the plugin was removed, so there is nothing left to send.
+    Game,
+}
 
-    thread::spawn(move || handle_client_events(reader, client_events));
-    thread::spawn(move || handle_server_events(writer, server_events));
+/// What happened next on a connection.
+enum Forward {
+    /// The server sent a message, or the websocket closed.
+    FromServer(Result<Message, ConnectionError>),
+    /// The game sent a message, `None` once it dropped [`Channels`].
+    FromGame(Option<Message>),
 }
 
-fn handle_client_events(mut reader: Reader<TcpStream>, client_events: mpsc::Sender<ClientEvent>) {
-    while let Ok(message) = reader.recv_message() {
-        let bytes = message.take_payload();
-        if let Ok(event) = serde_json::from_slice(&bytes) {
-            client_events.send(event).unwrap();
+/// Pass messages between the lobby and the game until either goes away.
+async fn forward_messages(
+    mut socket: WebSocket,
+    updates: &Sender<Update>,
+    server_events: &Receiver<Message>,
+) -> Disconnected {
+    loop {
+        // Racing picks either side at random when both are ready, so neither can starve the other
+        let next = future::race(async { Forward::FromServer(socket.recv().await) }, async {
+            Forward::FromGame(server_events.recv().await.ok())
+        });
+        match next.await {
+            Forward::FromServer(Ok(message)) => {
+                if let Some(event) = decode(&message) {
+                    let _ = updates.try_send(Update::Message(event));
+                }
+            }
+            Forward::FromServer(Err(reason)) => return Disconnected::Server(reason),
+            Forward::FromGame(Some(message)) => {
+                if socket.send(message).await.is_err() {
+                    return Disconnected::Server(ConnectionError::Closed);
+                }
+            }
+            Forward::FromGame(None) => {
+                socket.close("The game disconnected").await;
+                return Disconnected::Game;
+            }
         }
     }
 }
 
-fn handle_server_events(mut writer: Writer<TcpStream>, server_events: mpsc::Receiver<ServerEvent>) {
-    while let Ok(event) = server_events.recv() {
-        let text = serde_json::to_string(&event).unwrap();
-        writer.send_message(&OwnedMessage::Text(text)).unwrap();
+#[allow(clippy::too_many_arguments)] // Every update of the connection ends up in its own event
+fn translate_client_event(
+    channels: Res<Channels>,
+    mut client_event: EventWriter<ClientEvent>,
+    mut joined: EventWriter<ViewerJoined>,
+    mut left: EventWriter<ViewerLeft>,
+    mut viewers: ResMut<Viewers>,
+    mut state: ResMut<MinimapConnectionState>,
+    mut changed: EventWriter<ConnectionChanged>,
+    mut reconnecting: EventWriter<Reconnecting>,
+) {
+    while let Ok(update) = channels.updates.try_recv() {
+        let incoming = match update {
+            Update::Message(incoming) => incoming,
+            Update::State(new) => {
+                // Viewers of the old lobby are not in the new one
+                if new == MinimapConnectionState::CreatingLobby {
+                    for (_, viewer) in viewers.connections.drain() {
+                        left.send(ViewerLeft { viewer });
+                    }
+                }
+                // Resuming reports `Connecting` on every attempt
+                if *state != new {
+                    *state = new.clone();
+                    changed.send(ConnectionChanged { state: new });
+                }
+                continue;
+            }
+            Update::Reconnecting(attempt) => {
+                reconnecting.send(attempt);
+                continue;
+            }
+        };
+        match incoming {
+            Incoming::Client(event) => {
+                client_event.send(event);
+            }
+            Incoming::Presence(Presence::ViewerJoined { viewer }) => {
+                viewers
+                    .connections
+                    .insert(viewer.connection_id.clone(), viewer.clone());
+                joined.send(ViewerJoined { viewer });
+            }
+            Incoming::Presence(Presence::ViewerLeft { viewer }) => {
+                viewers.connections.remove(&viewer.connection_id);
+                left.send(ViewerLeft { viewer });
+            }
+        }
     }
 }
 
-fn translate_client_event(channels: Res<Channels>, mut client_event: EventWriter<ClientEvent>) {
-    let Ok(client_channel) = channels.client_events.try_lock() else {
-        return;
-    };
-
-    if let Ok(event) = client_channel.try_recv() {
-        client_event.send(event);
+fn translate_server_event(
+    channels: Res<Channels>,
+    encoding: Res<Encoding>,
+    mut server_event: EventReader<ServerEvent>,
+) {
+    for event in server_event.read() {
+        // Nothing is sent once the connection failed, the state tells the game why
+        if let Some(message) = encoding.encode(event) {
+            let _ = channels.server_events.try_send(message);
+        }
     }
 }
 
-fn translate_server_event(channels: Res<Channels>, mut server_event: EventReader<ServerEvent>) {
-    for event in server_event.read() {
-        channels.server_events.send(event.clone()).unwrap();
+fn translate_send_to_viewer(
+    channels: Res<Channels>,
+    encoding: Res<Encoding>,
+    mut send_to_viewer: EventReader<SendToViewer>,
+) {
+    for event in send_to_viewer.read() {
+        if let Some(message) = encoding.encode(event) {
+            let _ = channels.server_events.try_send(message);
+        }
     }
 }
 
@@ -310,10 +693,19 @@ impl Default for CssTimer {
 fn update_css(
     query: Query<(Entity, &OnMinimap)>,
     extra_css: Res<ExtraCss>,
+    mut changed: EventReader<ConnectionChanged>,
     mut server: EventWriter<ServerEvent>,
     mut timer: Local<CssTimer>,
+    mut last_css: Local<Option<String>>,
     time: Res<Time>,
 ) {
+    // Css sent while the connection was down never arrived, so send it again once connected
+    if changed
+        .read()
+        .any(|changed| changed.state == MinimapConnectionState::Connected)
+    {
+        *last_css = None;
+    }
     if !timer.0.tick(time.delta()).just_finished() {
         return;
     }
@@ -331,7 +723,210 @@ fn update_css(
     }
 
     css_string.push_str(&extra_css.0);
+
+    // The server replays the latest css to viewers that join later, so only send changes
+    if last_css.as_ref() == Some(&css_string) {
+        return;
+    }
+    *last_css = Some(css_string.clone());
     server.send(ServerEvent {
         data: ServerData::Css(css_string),
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// App running [`translate_client_event`], and the sending end of its updates.
+    fn app() -> (App, Sender<Update>) {
+        let (updates, update_recv) = async_channel::unbounded();
+        let (server_events, _) = async_channel::unbounded();
+        let (stop, _) = async_channel::bounded(1);
+
+        let mut app = App::new();
+        app.add_event::<ClientEvent>()
+            .add_event::<ViewerJoined>()
+            .add_event::<ViewerLeft>()
+            .add_event::<ConnectionChanged>()
+            .add_event::<Reconnecting>()
+            .init_resource::<Viewers>()
+            .init_resource::<MinimapConnectionState>()
+            .insert_resource(Channels {
+                updates: update_recv,
+                server_events,
+                _stop: stop,
+            })
+            .add_systems(Update, translate_client_event);
+        (app, updates)
+    }
+
+    /// States announced by [`ConnectionChanged`] since the last call.
+    fn changes(app: &mut App) -> Vec<MinimapConnectionState> {
+        app.world_mut()
+            .resource_mut::<Events<ConnectionChanged>>()
+            .drain()
+            .map(|changed| changed.state)
+            .collect()
+    }
+
+    fn viewer() -> Viewer {
+        Viewer {
+            opaque_user_id: String::from("U1"),
+            user_id: None,
+            role: Role::Viewer,
+            connection_id: String::from("a"),
+        }
+    }
+
+    #[test]
+    fn state_transitions() {
+        let (mut app, updates) = app();
+        for state in [
+            MinimapConnectionState::CreatingLobby,
+            MinimapConnectionState::Connecting,
+            MinimapConnectionState::Connected,
+        ] {
+            updates.try_send(Update::State(state)).unwrap();
+        }
+        app.update();
+
+        assert_eq!(
+            changes(&mut app),
+            [
+                MinimapConnectionState::CreatingLobby,
+                MinimapConnectionState::Connecting,
+                MinimapConnectionState::Connected,
+            ]
+        );
+        assert!(app
+            .world()
+            .resource::<MinimapConnectionState>()
+            .is_connected());
+    }
+
+    #[test]
+    fn reconnecting_reports_connecting_once() {
+        let (mut app, updates) = app();
+        updates
+            .try_send(Update::State(MinimapConnectionState::Connected))
+            .unwrap();
+        app.update();
+        changes(&mut app);
+
+        for attempt in 1..=2 {
+            updates
+                .try_send(Update::State(MinimapConnectionState::Connecting))
+                .unwrap();
+            updates
+                .try_send(Update::Reconnecting(Reconnecting {
+                    attempt,
+                    delay: Duration::from_secs(1),
+                    reason: ConnectionError::Closed,
+                }))
+                .unwrap();
+        }
+        app.update();
+
+        assert_eq!(changes(&mut app), [MinimapConnectionState::Connecting]);
+        let attempts: Vec<_> = app
+            .world_mut()
+            .resource_mut::<Events<Reconnecting>>()
+            .drain()
+            .map(|reconnecting| reconnecting.attempt)
+            .collect();
+        assert_eq!(attempts, [1, 2]);
+    }
+
+    #[test]
+    fn failed() {
+        let (mut app, updates) = app();
+        let reason = ConnectionError::Server(ServerError::NotAllowed(String::from("Wrong key!")));
+        updates
+            .try_send(Update::State(MinimapConnectionState::Failed(
+                reason.clone(),
+            )))
+            .unwrap();
+        app.update();
+
+        assert_eq!(
+            changes(&mut app),
+            [MinimapConnectionState::Failed(reason.clone())]
+        );
+        assert_eq!(
+            *app.world().resource::<MinimapConnectionState>(),
+            MinimapConnectionState::Failed(reason)
+        );
+    }
+
+    #[test]
+    fn css_is_sent_again_after_reconnecting() {
+        let (mut app, updates) = app();
+        app.add_event::<ServerEvent>()
+            .insert_resource(ExtraCss(String::from(".a {}")))
+            .init_resource::<Time>()
+            .add_systems(Update, update_css.after(translate_client_event));
+        let mut step = |state: Option<MinimapConnectionState>| {
+            if let Some(state) = state {
+                updates.try_send(Update::State(state)).unwrap();
+            }
+            app.world_mut()
+                .resource_mut::<Time>()
+                .advance_by(Duration::from_secs(1));
+            app.update();
+            app.world_mut()
+                .resource_mut::<Events<ServerEvent>>()
+                .drain()
+                .count()
+        };
+
+        assert_eq!(step(Some(MinimapConnectionState::Connected)), 1);
+        assert_eq!(step(None), 0);
+        assert_eq!(step(Some(MinimapConnectionState::Connecting)), 0);
+        assert_eq!(step(Some(MinimapConnectionState::Connected)), 1);
+    }
+
+    #[test]
+    fn viewers_come_and_go() {
+        let (mut app, updates) = app();
+        updates
+            .try_send(Update::Message(Incoming::Presence(
+                Presence::ViewerJoined { viewer: viewer() },
+            )))
+            .unwrap();
+        app.update();
+        assert_eq!(app.world().resource::<Viewers>().len(), 1);
+
+        updates
+            .try_send(Update::Message(Incoming::Presence(Presence::ViewerLeft {
+                viewer: viewer(),
+            })))
+            .unwrap();
+        app.update();
+        assert!(app.world().resource::<Viewers>().is_empty());
+    }
+
+    #[test]
+    fn new_lobby_has_no_viewers() {
+        let (mut app, updates) = app();
+        updates
+            .try_send(Update::Message(Incoming::Presence(
+                Presence::ViewerJoined { viewer: viewer() },
+            )))
+            .unwrap();
+        app.update();
+
+        updates
+            .try_send(Update::State(MinimapConnectionState::CreatingLobby))
+            .unwrap();
+        app.update();
+        assert!(app.world().resource::<Viewers>().is_empty());
+        let left: Vec<_> = app
+            .world_mut()
+            .resource_mut::<Events<ViewerLeft>>()
+            .drain()
+            .map(|left| left.viewer)
+            .collect();
+        assert_eq!(left, [viewer()]);
+    }
+}
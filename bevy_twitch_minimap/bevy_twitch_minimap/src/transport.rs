@@ -0,0 +1,21 @@
+//! Http requests and the websocket to the server, as futures for bevy's
+//! [`IoTaskPool`](bevy::tasks::IoTaskPool).
+//!
+//! Native builds talk tcp themselves, browser builds go through the `fetch` and `WebSocket` apis
+//! of the browser.
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native;
+#[cfg(target_arch = "wasm32")]
+mod web;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use native::{random, request, sleep, WebSocket};
+#[cfg(target_arch = "wasm32")]
+pub(crate) use web::{random, request, sleep, WebSocket};
+
+/// A websocket message with data, the transport answers pings by itself.
+pub(crate) enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
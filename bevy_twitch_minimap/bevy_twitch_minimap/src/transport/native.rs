@@ -0,0 +1,202 @@
+//! Transport over tcp, with rustls for tls.
+
+use std::io;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use async_io::Timer;
+use async_net::TcpStream;
+use async_tungstenite::tungstenite::client::IntoClientRequest;
+use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use async_tungstenite::tungstenite::protocol::CloseFrame;
+use async_tungstenite::{tungstenite, WebSocketStream};
+use futures_lite::{future, AsyncRead, AsyncWrite, StreamExt};
+use futures_rustls::pki_types::ServerName;
+use futures_rustls::rustls::{self, ClientConfig, RootCertStore};
+use futures_rustls::TlsConnector;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper::{header, Request, Uri};
+use smol_hyper::rt::FuturesIo;
+
+use super::Message;
+use crate::{ConnectionError, ServerError};
+
+/// How long to wait for the server to confirm closing the websocket.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Plain tcp for `http://` and `ws://` urls, tls for `https://` and `wss://`.
+type Socket = Box<dyn Io>;
+
+/// Anything [`Socket`] can be.
+trait Io: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Io for T {}
+
+/// Wait for `duration`.
+pub(crate) async fn sleep(duration: Duration) {
+    Timer::after(duration).await;
+}
+
+/// A random number from 0 to 1.
+pub(crate) fn random() -> f32 {
+    fastrand::f32()
+}
+
+/// Send a request without body, answered with the status and body of the response.
+pub(crate) async fn request(method: &str, url: &str) -> Result<(u16, String), ConnectionError> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|err: hyper::http::uri::InvalidUri| ConnectionError::Request(err.to_string()))?;
+    let socket = open(&uri)
+        .await
+        .map_err(|err| ConnectionError::Request(err.to_string()))?;
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(FuturesIo::new(socket))
+        .await
+        .map_err(|err| ConnectionError::Request(err.to_string()))?;
+
+    let host = uri.authority().map_or("", |authority| authority.as_str());
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(header::HOST, host)
+        .body(Empty::<Bytes>::new())
+        .map_err(|err| ConnectionError::Request(err.to_string()))?;
+    let exchange = async move {
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(|err| ConnectionError::Request(err.to_string()))?;
+        let status = response.status().as_u16();
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map_err(|err| ConnectionError::Request(err.to_string()))?
+            .to_bytes();
+        Ok((status, String::from_utf8_lossy(&body).into_owned()))
+    };
+    // The connection does the reading and writing, it finishes once `sender` is dropped
+    let (response, _) = future::zip(exchange, connection).await;
+    response
+}
+
+/// Websocket to a lobby.
+pub(crate) struct WebSocket(WebSocketStream<Socket>);
+
+impl WebSocket {
+    pub(crate) async fn connect(url: &str) -> Result<Self, ConnectionError> {
+        let request = url
+            .into_client_request()
+            .map_err(|err| ConnectionError::WebSocket(err.to_string()))?;
+        let socket = open(request.uri())
+            .await
+            .map_err(|err| ConnectionError::WebSocket(err.to_string()))?;
+        let (stream, _) = async_tungstenite::client_async(request, socket)
+            .await
+            .map_err(|err| match err {
+                // A wrong or rotated key will not get better by trying again
+                tungstenite::Error::Http(response)
+                    if matches!(response.status().as_u16(), 401 | 403) =>
+                {
+                    let body = response.body().as_deref().unwrap_or_default();
+                    let body = String::from_utf8_lossy(body).into_owned();
+                    ConnectionError::Server(ServerError::new(response.status().as_u16(), body))
+                }
+                err => ConnectionError::WebSocket(err.to_string()),
+            })?;
+        Ok(Self(stream))
+    }
+
+    /// The next message of the server, or why the connection is gone.
+    pub(crate) async fn recv(&mut self) -> Result<Message, ConnectionError> {
+        loop {
+            match self.0.next().await {
+                Some(Ok(tungstenite::Message::Text(text))) => {
+                    return Ok(Message::Text(text.to_string()))
+                }
+                Some(Ok(tungstenite::Message::Binary(bytes))) => {
+                    return Ok(Message::Binary(bytes.to_vec()))
+                }
+                // The server gives a reason when it closes the connection on purpose
+                Some(Ok(tungstenite::Message::Close(Some(frame)))) if !frame.reason.is_empty() => {
+                    let closed = ServerError::closed(frame.code.into(), frame.reason.to_string());
+                    return Err(ConnectionError::Server(closed));
+                }
+                Some(Ok(tungstenite::Message::Close(_)) | Err(_)) | None => {
+                    return Err(ConnectionError::Closed)
+                }
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+
+    pub(crate) async fn send(&mut self, message: Message) -> Result<(), ConnectionError> {
+        let message = match message {
+            Message::Text(text) => tungstenite::Message::Text(text.into()),
+            Message::Binary(bytes) => tungstenite::Message::Binary(bytes.into()),
+        };
+        self.0
+            .send(message)
+            .await
+            .map_err(|err| ConnectionError::WebSocket(err.to_string()))
+    }
+
+    /// Close the connection with a close frame giving `reason`.
+    pub(crate) async fn close(mut self, reason: &str) {
+        let frame = CloseFrame {
+            code: CloseCode::Normal,
+            reason: reason.into(),
+        };
+        if self.0.close(Some(frame)).await.is_err() {
+            return;
+        }
+        // The server answers with a close frame of its own, which ends the stream
+        let answered = async { while self.0.next().await.is_some() {} };
+        future::or(answered, async {
+            Timer::after(CLOSE_TIMEOUT).await;
+        })
+        .await;
+    }
+}
+
+/// Connect to the host of `uri`, with tls unless the scheme is `http` or `ws`.
+async fn open(uri: &Uri) -> io::Result<Socket> {
+    let host = uri
+        .host()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the url has no host"))?;
+    let plain = matches!(uri.scheme_str(), Some("http" | "ws"));
+    let port = uri.port_u16().unwrap_or(if plain { 80 } else { 443 });
+    let tcp = TcpStream::connect((host, port)).await?;
+    if plain {
+        return Ok(Box::new(tcp));
+    }
+
+    let name = ServerName::try_from(host.to_owned())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let tls = connector()?.connect(name, tcp).await?;
+    Ok(Box::new(tls))
+}
+
+/// Checks certificates against the Mozilla root certificates, so it works the same everywhere.
+fn connector() -> io::Result<TlsConnector> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    if let Some(config) = CONFIG.get() {
+        return Ok(TlsConnector::from(config.clone()));
+    }
+
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    // The game may link other crypto providers, so pick one instead of relying on the default
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(
+        CONFIG.get_or_init(|| Arc::new(config)).clone(),
+    ))
+}
@@ -0,0 +1,162 @@
+//! Transport through the `fetch` and `WebSocket` apis of the browser.
+
+use std::time::Duration;
+
+use async_channel::Receiver;
+use js_sys::{ArrayBuffer, Promise, Uint8Array};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{BinaryType, CloseEvent, Event, MessageEvent, RequestInit, Response};
+
+use super::Message;
+use crate::{ConnectionError, ServerError};
+
+/// Wait for `duration`.
+pub(crate) async fn sleep(duration: Duration) {
+    let millis = i32::try_from(duration.as_millis()).unwrap_or(i32::MAX);
+    let timeout = Promise::new(&mut |resolve, _| {
+        if let Some(window) = web_sys::window() {
+            let _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, millis);
+        }
+    });
+    let _ = JsFuture::from(timeout).await;
+}
+
+/// A random number from 0 to 1.
+pub(crate) fn random() -> f32 {
+    js_sys::Math::random() as f32
+}
+
+/// Send a request without body, answered with the status and body of the response.
+pub(crate) async fn request(method: &str, url: &str) -> Result<(u16, String), ConnectionError> {
+    let window = web_sys::window()
+        .ok_or_else(|| ConnectionError::Request(String::from("there is no browser window")))?;
+    let init = RequestInit::new();
+    init.set_method(method);
+    let request = web_sys::Request::new_with_str_and_init(url, &init)
+        .map_err(|err| ConnectionError::Request(describe(&err)))?;
+    let response: Response = JsFuture::from(window.fetch_with_request(&request))
+        .await
+        .and_then(JsCast::dyn_into)
+        .map_err(|err| ConnectionError::Request(describe(&err)))?;
+    let text = response
+        .text()
+        .map_err(|err| ConnectionError::Request(describe(&err)))?;
+    let body = JsFuture::from(text)
+        .await
+        .map_err(|err| ConnectionError::Request(describe(&err)))?;
+    Ok((response.status(), body.as_string().unwrap_or_default()))
+}
+
+/// Websocket to a lobby.
+pub(crate) struct WebSocket {
+    socket: web_sys::WebSocket,
+    /// Ends with why the websocket closed.
+    messages: Receiver<Result<Message, ConnectionError>>,
+    /// Called by the browser, they have to live as long as the websocket.
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>,
+}
+
+impl WebSocket {
+    pub(crate) async fn connect(url: &str) -> Result<Self, ConnectionError> {
+        let socket = web_sys::WebSocket::new(url)
+            .map_err(|err| ConnectionError::WebSocket(describe(&err)))?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+
+        let (opened, open) = async_channel::bounded(1);
+        let on_open = {
+            let opened = opened.clone();
+            Closure::<dyn FnMut(Event)>::new(move |_| {
+                let _ = opened.try_send(Ok(()));
+            })
+        };
+        socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+
+        let (sender, messages) = async_channel::unbounded();
+        let closing = sender.clone();
+        let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+            let data = event.data();
+            let message = match data.as_string() {
+                Some(text) => Message::Text(text),
+                None => match data.dyn_into::<ArrayBuffer>() {
+                    Ok(buffer) => Message::Binary(Uint8Array::new(&buffer).to_vec()),
+                    Err(_) => return,
+                },
+            };
+            let _ = sender.try_send(Ok(message));
+        });
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+        // Browsers do not tell why a websocket failed, only that it closed
+        let on_close = Closure::<dyn FnMut(CloseEvent)>::new(move |event: CloseEvent| {
+            // The server gives a reason when it closes the connection on purpose
+            let closed = match event.reason() {
+                reason if reason.is_empty() || !event.was_clean() => ConnectionError::Closed,
+                reason => ConnectionError::Server(ServerError::closed(event.code(), reason)),
+            };
+            let _ = closing.try_send(Err(closed));
+            closing.close();
+            let reason = match event.reason() {
+                reason if reason.is_empty() => format!("closed with code {}", event.code()),
+                reason => reason,
+            };
+            let _ = opened.try_send(Err(reason));
+        });
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+        let opened = open.recv().await;
+        socket.set_onopen(None);
+        let socket = Self {
+            socket,
+            messages,
+            _on_message: on_message,
+            _on_close: on_close,
+        };
+        match opened {
+            Ok(Ok(())) => Ok(socket),
+            Ok(Err(reason)) => Err(ConnectionError::WebSocket(reason)),
+            Err(err) => Err(ConnectionError::WebSocket(err.to_string())),
+        }
+    }
+
+    /// The next message of the server, or why the connection is gone.
+    pub(crate) async fn recv(&mut self) -> Result<Message, ConnectionError> {
+        self.messages
+            .recv()
+            .await
+            .unwrap_or(Err(ConnectionError::Closed))
+    }
+
+    pub(crate) async fn send(&mut self, message: Message) -> Result<(), ConnectionError> {
+        match message {
+            Message::Text(text) => self.socket.send_with_str(&text),
+            Message::Binary(bytes) => self.socket.send_with_u8_array(&bytes),
+        }
+        .map_err(|err| ConnectionError::WebSocket(describe(&err)))
+    }
+
+    /// Close the connection with a close frame giving `reason`.
+    pub(crate) async fn close(self, reason: &str) {
+        // The browser finishes the closing handshake by itself
+        let _ = self.socket.close_with_code_and_reason(1000, reason);
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        // The callbacks are dropped with this, the browser must not call them anymore
+        self.socket.set_onmessage(None);
+        self.socket.set_onclose(None);
+        let _ = self.socket.close();
+    }
+}
+
+/// The message of a javascript error.
+fn describe(err: &JsValue) -> String {
+    match err.dyn_ref::<js_sys::Error>() {
+        Some(err) => err.message().into(),
+        None => err.as_string().unwrap_or_else(|| format!("{err:?}")),
+    }
+}
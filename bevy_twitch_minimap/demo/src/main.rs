@@ -19,9 +19,20 @@ fn main() {
                 origin: Vec2::new(-100.0, -100.0),
             },
             auto_connect: Some(Connect::new_with_default_host(CHANNEL.into())),
+            encoding: Encoding::MessagePack,
+            reconnect: Backoff::default(),
         })
         .add_systems(Startup, (setup,))
-        .add_systems(Update, (print_client_events, move_player, update_color))
+        .add_systems(
+            Update,
+            (
+                print_client_events,
+                print_connection_changes,
+                move_player,
+                update_color,
+                toggle_connection,
+            ),
+        )
         .run();
 }
 
@@ -48,7 +59,30 @@ fn setup(
 
 fn print_client_events(mut events: EventReader<ClickEvent>) {
     for event in events.read() {
-        println!("{} clicked on {}, {}", event.user_id, event.x, event.y);
+        println!(
+            "{} clicked on {}, {}",
+            event.viewer.opaque_user_id, event.x, event.y
+        );
+    }
+}
+
+fn print_connection_changes(
+    mut events: EventReader<ConnectionChanged>,
+    mut reconnecting: EventReader<Reconnecting>,
+) {
+    for event in events.read() {
+        match &event.state {
+            MinimapConnectionState::Failed(err) => println!("Minimap failed: {err}"),
+            state => println!("Minimap is {state:?}"),
+        }
+    }
+    for event in reconnecting.read() {
+        println!(
+            "Minimap reconnects in {:.1}s (attempt {}): {}",
+            event.delay.as_secs_f32(),
+            event.attempt,
+            event.reason
+        );
     }
 }
 
@@ -92,3 +126,17 @@ fn update_color(
         data.color = data.color.rotate_hue(60.0);
     }
 }
+
+/// Leave the lobby with escape, connect again with enter.
+fn toggle_connection(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut connect: EventWriter<Connect>,
+    mut disconnect: EventWriter<Disconnect>,
+) {
+    if keyboard.just_pressed(KeyCode::Escape) {
+        disconnect.send(Disconnect);
+    }
+    if keyboard.just_pressed(KeyCode::Enter) {
+        connect.send(Connect::new_with_default_host(CHANNEL.into()));
+    }
+}
@@ -12,3 +12,16 @@ public class BubbleData
     public string userId = null;
     public string itemType = null;
 }
+
+[System.Serializable]
+public class ViewerMessage
+{
+    public BubbleData data = null;
+}
+
+[System.Serializable]
+public class StreamerLogin
+{
+    public string key = null;
+    public string url = null;
+}
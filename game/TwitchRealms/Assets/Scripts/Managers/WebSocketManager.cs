@@ -11,7 +11,7 @@ using System.Threading;
 public class WebSocketManager : MonoBehaviour
 {
     bool sent;
-    string url = "https://websocket.matissetec.dev/lobby/new?user=";
+    string url = "https://websocket.matissetec.dev/lobby/new?protocol.version=1&protocol.features=css&protocol.features=reset&user=";
     private WebSocket ws;
     private bool isRunning = false;
     [SerializeField]
@@ -68,8 +68,8 @@ public class WebSocketManager : MonoBehaviour
         {
             try
             {
-                BubbleData data = JsonUtility.FromJson<BubbleData>(_messageEventData);
-                if (data.userId != null)
+                BubbleData data = JsonUtility.FromJson<ViewerMessage>(_messageEventData).data;
+                if (data != null && data.userId != null)
                 {
                     float t = 5 * 5;
                     _bubbleGenerator.SpawnBubble(data.bubbleColor, data.bubbleSize, new Vector2(Remap(data.x, 0, 1, -t, t), Remap(data.y, 0, 1, -t, t)));
@@ -161,7 +161,8 @@ public class WebSocketManager : MonoBehaviour
             string responseText = request.downloadHandler.text;
             Debug.Log("Response: " + responseText);
 
-            ws = new WebSocket($"wss://websocket.matissetec.dev/lobby/connect/streamer?user={channelId}&key={responseText}");
+            StreamerLogin login = JsonUtility.FromJson<StreamerLogin>(responseText);
+            ws = new WebSocket(login.url);
 
             // Attach event handlers
             ws.OnMessage += OnMessageReceived;
@@ -0,0 +1,176 @@
+//! Endpoints for operating the server, protected by the admin token
+
+use std::time::Instant;
+
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::json::Json;
+use rocket::{Request, State};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+
+use twitch_minimap_protocol::LobbyStatus;
+
+use crate::{Errors, Lobbies, ResultExt};
+
+/// Configuration of the admin endpoints
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct AdminConfig {
+    /// Token required for the admin endpoints, they are disabled if this is not set
+    admin_token: Option<String>,
+}
+
+/// Manages the [`AdminConfig`]
+pub fn fairing() -> AdHoc {
+    AdHoc::config::<AdminConfig>()
+}
+
+/// Request guard for requests with `Authorization: Bearer <admin_token>`
+#[derive(Debug, Clone, Copy)]
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(expected) = request
+            .rocket()
+            .state::<AdminConfig>()
+            .and_then(|config| config.admin_token.as_deref())
+        else {
+            return Outcome::Error((Status::Forbidden, "Admin endpoints are disabled"));
+        };
+
+        let token = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+        match token {
+            // Takes as long however many bytes match, so the token can not be guessed byte by byte
+            Some(token) if bool::from(token.as_bytes().ct_eq(expected.as_bytes())) => {
+                Outcome::Success(Admin)
+            }
+            Some(_) => Outcome::Error((Status::Forbidden, "Wrong admin token")),
+            None => Outcome::Error((Status::Unauthorized, "Missing admin token")),
+        }
+    }
+}
+
+/// Stats of a lobby, for operators
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LobbyStats {
+    /// Streamer who created the lobby
+    owner: String,
+    /// The public status
+    #[serde(flatten)]
+    status: LobbyStatus,
+    /// Seconds since the lobby was created
+    age: u64,
+    /// Seconds since the streamer last sent something
+    streamer_idle: u64,
+    /// Seconds since a viewer last connected or disconnected
+    viewers_idle: u64,
+}
+
+/// List every lobby with its stats
+#[get("/lobbies")]
+pub fn list_lobbies(
+    _admin: Admin,
+    lobbies: &State<Lobbies>,
+) -> Result<Json<Vec<LobbyStats>>, Errors> {
+    let now = Instant::now();
+    let since = |instant: Instant| now.saturating_duration_since(instant).as_secs();
+    let channels = lobbies.channels.read().unknown()?;
+
+    let mut stats = channels
+        .values()
+        .filter_map(|lobby| {
+            let activity = lobby.activity.get()?;
+            Some(LobbyStats {
+                owner: lobby.owner.to_string(),
+                status: lobby.status(),
+                age: since(lobby.created),
+                streamer_idle: since(activity.streamer_seen),
+                viewers_idle: since(activity.viewers_seen),
+            })
+        })
+        .collect::<Vec<_>>();
+    stats.sort_by(|a, b| a.owner.cmp(&b.owner));
+
+    Ok(Json(stats))
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+    use rocket::serde::json::Value;
+
+    use super::*;
+    use crate::tests::test_rocket;
+
+    fn client(admin_token: Option<&str>) -> Client {
+        let mut rocket = test_rocket();
+        if let Some(token) = admin_token {
+            let figment = rocket.figment().clone().merge(("admin_token", token));
+            rocket = rocket.configure(figment);
+        }
+        Client::tracked(rocket).unwrap()
+    }
+
+    #[test]
+    fn disabled() {
+        let client = client(None);
+        let response = client
+            .get("/admin/lobbies")
+            .header(Header::new("Authorization", "Bearer anything"))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn missing_token() {
+        let client = client(Some("admin"));
+        let response = client.get("/admin/lobbies").dispatch();
+
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn wrong_token() {
+        let client = client(Some("admin"));
+        let response = client
+            .get("/admin/lobbies")
+            .header(Header::new("Authorization", "Bearer nope"))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn list() {
+        let client = client(Some("admin"));
+        client.post("/lobby/new?user=viv").dispatch();
+        client.post("/lobby/new?user=alice").dispatch();
+
+        let response = client
+            .get("/admin/lobbies")
+            .header(Header::new("Authorization", "Bearer admin"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let lobbies = response.into_json::<Vec<Value>>().unwrap();
+        assert_eq!(lobbies.len(), 2);
+        assert_eq!(lobbies[0]["owner"], "alice");
+        assert_eq!(lobbies[1]["owner"], "viv");
+        assert_eq!(lobbies[1]["game"], "waiting");
+        assert_eq!(lobbies[1]["viewers"], 0);
+    }
+}
@@ -0,0 +1,203 @@
+//! Verification of the twitch extension JWT viewers send when connecting
+
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use rocket::fairing::AdHoc;
+use serde::{Deserialize, Serialize};
+
+use crate::Errors;
+
+pub use twitch_minimap_protocol::Role;
+
+/// The claims twitch puts in the extension JWT
+///
+/// See <https://dev.twitch.tv/docs/extensions/reference/#jwt-schema>
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionClaims {
+    /// Expiration time as a unix timestamp
+    pub exp: u64,
+    /// Opaque id of the viewer, always present
+    pub opaque_user_id: String,
+    /// Twitch id of the viewer, only present if they shared their identity
+    #[serde(default)]
+    pub user_id: Option<String>,
+    /// Channel the extension is running on
+    pub channel_id: String,
+    /// Role of the viewer in the channel
+    pub role: Role,
+}
+
+/// Identity of a connected viewer, stamped on every message they send to the game
+pub type ViewerIdentity = twitch_minimap_protocol::Viewer;
+
+impl ExtensionClaims {
+    /// Create the identity for a new connection from verified claims
+    pub fn into_viewer(self) -> ViewerIdentity {
+        ViewerIdentity {
+            opaque_user_id: self.opaque_user_id,
+            user_id: self.user_id,
+            role: self.role,
+            connection_id: uuid::Uuid::new_v4().to_string(),
+        }
+    }
+}
+
+/// Configuration needed to verify tokens
+#[derive(Deserialize, Debug)]
+struct AuthConfig {
+    /// The base64 encoded extension secret from the twitch developer console
+    extension_secret: String,
+}
+
+/// Verifies extension tokens with the configured secret
+pub struct TokenVerifier {
+    /// Key used to check the HS256 signature
+    key: DecodingKey,
+    /// Validation rules for the token
+    validation: Validation,
+}
+
+impl std::fmt::Debug for TokenVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenVerifier").finish_non_exhaustive()
+    }
+}
+
+impl TokenVerifier {
+    /// Create a verifier from the base64 encoded extension secret
+    ///
+    /// An empty secret is refused, anyone could sign tokens with it.
+    pub fn from_base64_secret(secret: &str) -> Result<Self, String> {
+        // Only padding decodes to no bytes at all
+        if secret.trim().trim_end_matches('=').is_empty() {
+            return Err("extension_secret is empty".into());
+        }
+        let key = DecodingKey::from_base64_secret(secret.trim())
+            .map_err(|err| format!("extension_secret is not valid base64: {err}"))?;
+        Ok(Self {
+            key,
+            validation: Validation::new(Algorithm::HS256),
+        })
+    }
+
+    /// Verify the token and make sure it was issued for `channel`
+    pub fn verify(&self, token: &str, channel: &str) -> Result<ExtensionClaims, Errors> {
+        let claims = jsonwebtoken::decode::<ExtensionClaims>(token, &self.key, &self.validation)
+            .map_err(|err| match err.kind() {
+                ErrorKind::ExpiredSignature => Errors::TokenExpired("Token has expired".into()),
+                _ => Errors::Unauthorized("Invalid token".into()),
+            })?
+            .claims;
+
+        if claims.channel_id != channel {
+            return Err(Errors::NotAllowed(
+                "Token was issued for a different channel".into(),
+            ));
+        }
+
+        Ok(claims)
+    }
+}
+
+/// Reads the extension secret from the config and manages a [`TokenVerifier`]
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Extension token verifier", |rocket| async {
+        let verifier = rocket
+            .figment()
+            .extract::<AuthConfig>()
+            .map_err(|err| format!("{err}"))
+            .and_then(|config| TokenVerifier::from_base64_secret(&config.extension_secret));
+
+        match verifier {
+            Ok(verifier) => Ok(rocket.manage(verifier)),
+            Err(err) => {
+                log::error!("Failed to configure token verification: {err}");
+                Err(rocket)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use jsonwebtoken::{EncodingKey, Header};
+
+    use super::*;
+
+    /// Base64 encoded secret used for tests
+    pub const SECRET: &str = "c2VjcmV0LXVzZWQtZm9yLXRlc3Rpbmc=";
+
+    /// Create a signed token for `channel` that expires `expires_in` seconds from now
+    pub fn token(channel: &str, role: Role, expires_in: i64) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let claims = ExtensionClaims {
+            exp: now.saturating_add_signed(expires_in),
+            opaque_user_id: "U123".into(),
+            user_id: Some("123".into()),
+            channel_id: channel.into(),
+            role,
+        };
+        let key = EncodingKey::from_base64_secret(SECRET).unwrap();
+        jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &key).unwrap()
+    }
+
+    fn verifier() -> TokenVerifier {
+        TokenVerifier::from_base64_secret(SECRET).unwrap()
+    }
+
+    #[test]
+    fn valid() {
+        let claims = verifier()
+            .verify(&token("viv", Role::Viewer, 3600), "viv")
+            .unwrap();
+
+        assert_eq!(claims.opaque_user_id, "U123");
+        assert_eq!(claims.user_id.as_deref(), Some("123"));
+        assert_eq!(claims.role, Role::Viewer);
+    }
+
+    #[test]
+    fn expired() {
+        let res = verifier().verify(&token("viv", Role::Viewer, -3600), "viv");
+        assert!(matches!(res, Err(Errors::TokenExpired(_))));
+    }
+
+    #[test]
+    fn wrong_channel() {
+        let res = verifier().verify(&token("viv", Role::Viewer, 3600), "other");
+        assert!(matches!(res, Err(Errors::NotAllowed(_))));
+    }
+
+    #[test]
+    fn wrong_secret() {
+        let verifier = TokenVerifier::from_base64_secret("b3RoZXItc2VjcmV0").unwrap();
+        let res = verifier.verify(&token("viv", Role::Viewer, 3600), "viv");
+        assert!(matches!(res, Err(Errors::Unauthorized(_))));
+    }
+
+    #[test]
+    fn empty_secret() {
+        for secret in ["", "  ", "=="] {
+            let res = TokenVerifier::from_base64_secret(secret);
+            assert_eq!(res.err().as_deref(), Some("extension_secret is empty"));
+        }
+    }
+
+    #[test]
+    fn invalid_secret() {
+        assert!(TokenVerifier::from_base64_secret("not base64!").is_err());
+    }
+
+    #[test]
+    fn garbage() {
+        let res = verifier().verify("not.a.token", "viv");
+        assert!(matches!(res, Err(Errors::Unauthorized(_))));
+    }
+}
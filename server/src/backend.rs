@@ -0,0 +1,836 @@
+//! Lets the connections of a lobby be spread over multiple server instances
+//!
+//! Every instance keeps its own copy of the lobby, the backend carries the messages between them.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use redis::aio::{MultiplexedConnection, PubSub};
+use redis::{AsyncCommands, ExistenceCheck, RedisResult, SetExpiry, SetOptions};
+use rocket::figment::Figment;
+use rocket::futures::future::{self, BoxFuture};
+use rocket::futures::StreamExt;
+use rocket::serde::json::serde_json;
+use rocket::tokio::sync::watch;
+use serde::Deserialize;
+use twitch_minimap_protocol::Presence;
+
+use crate::metrics::{Direction, Metrics};
+use crate::registry::LobbyRecord;
+use crate::sticky::sticky_key;
+use crate::targeted::addressed;
+use crate::{ActivityTracker, LobbyChannels, StreamerState, UserId};
+
+/// Prefix of every key and channel, so the instances can share a Redis with other users
+const PREFIX: &str = "minimap";
+
+/// Channel suffix announcing that a lobby was closed, the payload is the reason
+const CLOSED: &str = "closed";
+
+/// Which backend connects the instances
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct BackendConfig {
+    /// Url of the Redis shared by the instances, lobbies only live in this instance if not set
+    lobby_backend: Option<String>,
+    /// Seconds an announced lobby is kept after its instance last refreshed it
+    lobby_record_ttl: u64,
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        Self {
+            lobby_backend: None,
+            lobby_record_ttl: 60,
+        }
+    }
+}
+
+/// Open the backend configured in `figment`
+pub async fn open(figment: &Figment) -> Result<Arc<dyn LobbyBackend>, String> {
+    let config = figment
+        .extract::<BackendConfig>()
+        .map_err(|err| format!("Invalid lobby backend config: {err}"))?;
+    let Some(url) = config.lobby_backend else {
+        return Ok(Arc::new(InProcess));
+    };
+    let backend = RedisBackend::connect(&url, config.lobby_record_ttl.max(1))
+        .await
+        .map_err(|err| format!("Could not connect to the lobby backend: {err}"))?;
+    log::info!("Sharing lobbies through Redis");
+    Ok(Arc::new(backend))
+}
+
+/// Who a message sent through the backend is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// The connected game
+    Game,
+    /// The viewers, or the one viewer the message is addressed to
+    Viewers,
+}
+
+impl Route {
+    /// Channel suffix of messages on this route
+    const fn topic(self) -> &'static str {
+        match self {
+            Self::Game => "game",
+            Self::Viewers => "viewers",
+        }
+    }
+}
+
+/// The copy of a lobby on this instance, which the backend delivers messages to
+#[derive(Debug, Clone)]
+pub struct LocalLobby {
+    /// Owner of the lobby
+    pub owner: UserId,
+    /// Channels of the connections on this instance
+    pub channels: LobbyChannels,
+    /// Connection state of the streamer on this instance
+    pub streamer: watch::Sender<StreamerState>,
+    /// Activity of the lobby on this instance
+    pub activity: ActivityTracker,
+    /// Reason the lobby was closed, once it is
+    pub closed: watch::Receiver<Option<String>>,
+    /// Metrics of the lobby
+    pub metrics: Metrics,
+}
+
+impl LocalLobby {
+    /// Hand `message` to the connections on this instance
+    ///
+    /// Messages for the game are dropped while its queue is full, e.g. while it is away, so
+    /// viewers never wait for a game that may not come back.
+    fn deliver(&self, route: Route, message: ws::Message) {
+        match route {
+            Route::Game => {
+                let _ = self.channels.client_to_streamer.try_send(message);
+            }
+            Route::Viewers => self.to_viewers(message),
+        }
+    }
+
+    /// Send a message of the game to the viewers, or only the viewer it is addressed to
+    fn to_viewers(&self, message: ws::Message) {
+        let Some((to, message)) = addressed(&message) else {
+            self.channels.streamer_to_client.send(message);
+            return;
+        };
+        let sent = self.channels.viewers.send(&to, &message);
+        if sent == 0 {
+            log::debug!("STREAM: No viewer {to} to send a message to");
+        }
+        for _ in 0..sent {
+            self.metrics.forwarded(Direction::ToViewer, message.len());
+        }
+    }
+}
+
+/// Resolves once the lobby is closed, to the reason if another instance closed it
+pub type Delivery = BoxFuture<'static, Option<String>>;
+
+/// Carries lobbies and their messages between the server instances
+#[rocket::async_trait]
+pub trait LobbyBackend: Send + Sync + fmt::Debug {
+    /// Make a lobby created or changed on this instance known to the others
+    async fn announce(&self, record: &LobbyRecord);
+
+    /// Keep the announcement of a lobby this instance looks after from expiring
+    ///
+    /// Announcements of an instance that went away without withdrawing them expire, so the
+    /// channel can get a new lobby.
+    async fn refresh(&self, record: &LobbyRecord);
+
+    /// Tell every instance that the lobby of `owner` was closed
+    async fn withdraw(&self, owner: &str, reason: &str);
+
+    /// Look up a lobby another instance announced
+    async fn find(&self, owner: &str) -> Option<LobbyRecord>;
+
+    /// Send `message` to the connections of `lobby` on every instance
+    async fn send(&self, lobby: &LocalLobby, route: Route, message: ws::Message);
+
+    /// Subscribe `lobby` to the messages sent to it
+    ///
+    /// Nothing is delivered until the returned [`Delivery`] is polled.
+    async fn attach(&self, lobby: LocalLobby) -> Delivery;
+}
+
+/// All connections of a lobby are on this instance, messages are delivered directly
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InProcess;
+
+#[rocket::async_trait]
+impl LobbyBackend for InProcess {
+    async fn announce(&self, _record: &LobbyRecord) {}
+
+    async fn refresh(&self, _record: &LobbyRecord) {}
+
+    async fn withdraw(&self, _owner: &str, _reason: &str) {}
+
+    async fn find(&self, _owner: &str) -> Option<LobbyRecord> {
+        None
+    }
+
+    async fn send(&self, lobby: &LocalLobby, route: Route, message: ws::Message) {
+        lobby.deliver(route, message);
+    }
+
+    async fn attach(&self, _lobby: LocalLobby) -> Delivery {
+        Box::pin(future::ready(None))
+    }
+}
+
+/// What a connection uses to reach the other side of its lobby
+#[derive(Debug, Clone)]
+pub struct LobbyLink {
+    /// Carries the messages
+    backend: Arc<dyn LobbyBackend>,
+    /// The lobby on this instance
+    local: LocalLobby,
+}
+
+impl LobbyLink {
+    /// Reach `local` and its copies through `backend`
+    pub fn new(backend: Arc<dyn LobbyBackend>, local: LocalLobby) -> Self {
+        Self { backend, local }
+    }
+
+    /// Send `message` to the game or the viewers, wherever they are connected
+    pub async fn send(&self, route: Route, message: ws::Message) {
+        self.backend.send(&self.local, route, message).await;
+    }
+}
+
+/// Shares lobbies through Redis
+///
+/// Lobby records are stored as keys, so any instance can find them. They expire unless they are
+/// refreshed. The sticky messages of a lobby are kept in a hash next to its record, so copies
+/// made later can catch up on them. Messages are published to a channel per lobby and route,
+/// every instance with a copy of the lobby subscribes to them.
+pub struct RedisBackend {
+    /// Opens the subscriptions
+    client: redis::Client,
+    /// Shared connection for everything else
+    connection: MultiplexedConnection,
+    /// Seconds until a record that is not refreshed expires
+    record_ttl: u64,
+}
+
+impl fmt::Debug for RedisBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisBackend").finish_non_exhaustive()
+    }
+}
+
+impl RedisBackend {
+    /// Connect to the Redis at `url`, records expire `record_ttl` seconds after the last refresh
+    pub async fn connect(url: &str, record_ttl: u64) -> RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        let connection = client.get_multiplexed_async_connection().await?;
+        Ok(Self {
+            client,
+            connection,
+            record_ttl,
+        })
+    }
+}
+
+/// Key of the record of the lobby of `owner`
+fn key(owner: &str) -> String {
+    format!("{PREFIX}:lobby:{owner}")
+}
+
+/// Key of the hash holding the sticky messages of the lobby of `owner`, by sticky key
+fn sticky(owner: &str) -> String {
+    format!("{PREFIX}:sticky:{owner}")
+}
+
+/// Channel of the lobby of `owner`
+fn channel(owner: &str, topic: &str) -> String {
+    format!("{PREFIX}:{owner}:{topic}")
+}
+
+/// Tag the payload so text and binary messages stay apart, other messages are not sent
+fn encode(message: ws::Message) -> Option<Vec<u8>> {
+    let (tag, payload) = match message {
+        ws::Message::Text(text) => (b't', text.into_bytes()),
+        ws::Message::Binary(bytes) => (b'b', bytes),
+        _ => return None,
+    };
+    let mut encoded = Vec::with_capacity(payload.len() + 1);
+    encoded.push(tag);
+    encoded.extend_from_slice(&payload);
+    Some(encoded)
+}
+
+/// Prefix the payload made by [`encode`] with when it was sent, to replay sticky messages in order
+///
+/// Messages sent by this instance within the same millisecond are told apart by a counter.
+fn stamp(payload: &[u8]) -> Vec<u8> {
+    static SENT: AtomicU64 = AtomicU64::new(0);
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| {
+            u64::try_from(since.as_millis()).unwrap_or(u64::MAX)
+        });
+    let mut stamped = millis.to_be_bytes().to_vec();
+    stamped.extend_from_slice(&SENT.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    stamped.extend_from_slice(payload);
+    stamped
+}
+
+/// Split a value made by [`stamp`] into when it was sent and the message
+fn unstamp(value: &[u8]) -> Option<([u8; 16], ws::Message)> {
+    let (sent, payload) = value.split_first_chunk::<16>()?;
+    Some((*sent, decode(payload)?))
+}
+
+/// Turn a payload made by [`encode`] back into a message
+fn decode(payload: &[u8]) -> Option<ws::Message> {
+    match payload.split_first()? {
+        (b't', text) => String::from_utf8(text.to_vec()).ok().map(ws::Message::Text),
+        (b'b', bytes) => Some(ws::Message::Binary(bytes.to_vec())),
+        _ => None,
+    }
+}
+
+#[rocket::async_trait]
+impl LobbyBackend for RedisBackend {
+    async fn announce(&self, record: &LobbyRecord) {
+        let Ok(value) = serde_json::to_string(record) else {
+            return;
+        };
+        let options = SetOptions::default().with_expiration(SetExpiry::EX(self.record_ttl));
+        let res: RedisResult<()> = self
+            .connection
+            .clone()
+            .set_options(key(&record.owner), value, options)
+            .await;
+        if let Err(err) = res {
+            log::error!("Could not announce lobby of {}: {err}", record.owner);
+        }
+    }
+
+    async fn refresh(&self, record: &LobbyRecord) {
+        let Ok(value) = serde_json::to_string(record) else {
+            return;
+        };
+        let mut connection = self.connection.clone();
+        let ttl = i64::try_from(self.record_ttl).unwrap_or(i64::MAX);
+        let res: RedisResult<()> = async {
+            connection
+                .expire::<_, ()>(sticky(&record.owner), ttl)
+                .await?;
+            let kept: bool = connection.expire(key(&record.owner), ttl).await?;
+            if !kept {
+                // It expired, e.g. while Redis was unreachable, or was never announced like a
+                // restored lobby. The key may have been rotated elsewhere, so only a missing
+                // record is replaced.
+                let options = SetOptions::default()
+                    .conditional_set(ExistenceCheck::NX)
+                    .with_expiration(SetExpiry::EX(self.record_ttl));
+                connection
+                    .set_options::<_, _, ()>(key(&record.owner), value, options)
+                    .await?;
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(err) = res {
+            log::error!("Could not refresh lobby of {}: {err}", record.owner);
+        }
+    }
+
+    async fn withdraw(&self, owner: &str, reason: &str) {
+        let mut connection = self.connection.clone();
+        let res: RedisResult<()> = async {
+            connection
+                .del::<_, ()>(&[key(owner), sticky(owner)])
+                .await?;
+            connection.publish(channel(owner, CLOSED), reason).await
+        }
+        .await;
+        if let Err(err) = res {
+            log::error!("Could not withdraw lobby of {owner}: {err}");
+        }
+    }
+
+    async fn find(&self, owner: &str) -> Option<LobbyRecord> {
+        let value: Option<String> = self
+            .connection
+            .clone()
+            .get(key(owner))
+            .await
+            .map_err(|err| log::error!("Could not look up lobby of {owner}: {err}"))
+            .ok()?;
+        serde_json::from_str(&value?)
+            .map_err(|err| log::warn!("Skipping unreadable lobby of {owner}: {err}"))
+            .ok()
+    }
+
+    async fn send(&self, lobby: &LocalLobby, route: Route, message: ws::Message) {
+        let sticky_key = (route == Route::Viewers && addressed(&message).is_none())
+            .then(|| sticky_key(&message))
+            .flatten();
+        let Some(payload) = encode(message) else {
+            return;
+        };
+        let mut connection = self.connection.clone();
+        let res: RedisResult<()> = async {
+            connection
+                .publish::<_, _, ()>(channel(&lobby.owner, route.topic()), &payload)
+                .await?;
+            if let Some(sticky_key) = sticky_key {
+                connection
+                    .hset::<_, _, _, ()>(sticky(&lobby.owner), sticky_key, stamp(&payload))
+                    .await?;
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(err) = res {
+            log::error!("Could not send to lobby of {}: {err}", lobby.owner);
+        }
+    }
+
+    async fn attach(&self, lobby: LocalLobby) -> Delivery {
+        let channels = [Route::Game.topic(), Route::Viewers.topic(), CLOSED]
+            .map(|topic| channel(&lobby.owner, topic));
+        let subscribed = async {
+            let mut pubsub = self.client.get_async_pubsub().await?;
+            pubsub.subscribe(&channels[..]).await?;
+            Ok::<_, redis::RedisError>(pubsub)
+        };
+        match subscribed.await {
+            Ok(pubsub) => {
+                // Looked up after subscribing, so nothing sent in between is missed
+                let snapshot = self.snapshot(&lobby.owner).await;
+                lobby.channels.streamer_to_client.seed(snapshot);
+                Box::pin(receive(pubsub, lobby))
+            }
+            Err(err) => {
+                log::error!("Could not subscribe to lobby of {}: {err}", lobby.owner);
+                Box::pin(future::ready(None))
+            }
+        }
+    }
+}
+
+impl RedisBackend {
+    /// The sticky messages of the lobby of `owner` stored so far, oldest first
+    async fn snapshot(&self, owner: &str) -> Vec<(String, ws::Message)> {
+        let stored: HashMap<String, Vec<u8>> =
+            match self.connection.clone().hgetall(sticky(owner)).await {
+                Ok(stored) => stored,
+                Err(err) => {
+                    log::error!("Could not look up sticky messages of {owner}: {err}");
+                    return Vec::new();
+                }
+            };
+        let mut snapshot = stored
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let (sent, message) = unstamp(&value)?;
+                Some((sent, key, message))
+            })
+            .collect::<Vec<_>>();
+        snapshot.sort_by_key(|(sent, ..)| *sent);
+        snapshot
+            .into_iter()
+            .map(|(_, key, message)| (key, message))
+            .collect()
+    }
+}
+
+/// Deliver what is published to `lobby` until it is closed
+async fn receive(pubsub: PubSub, lobby: LocalLobby) -> Option<String> {
+    let game = channel(&lobby.owner, Route::Game.topic());
+    let viewers = channel(&lobby.owner, Route::Viewers.topic());
+    let mut messages = pubsub.into_on_message();
+    let mut closed = lobby.closed.clone();
+
+    loop {
+        if closed.borrow_and_update().is_some() {
+            return None;
+        }
+        rocket::tokio::select! {
+            message = messages.next() => {
+                let Some(message) = message else {
+                    let owner = &lobby.owner;
+                    log::error!("Lost the lobby backend, lobby of {owner} stays on this instance");
+                    return None;
+                };
+                let channel = message.get_channel_name();
+                if channel != game && channel != viewers {
+                    return Some(String::from_utf8_lossy(message.get_payload_bytes()).into_owned());
+                }
+                let Some(payload) = decode(message.get_payload_bytes()) else {
+                    continue;
+                };
+                if channel == viewers {
+                    // The game is connected to another instance, let viewers connect here too
+                    lobby.streamer.send_if_modified(|state| {
+                        let waiting = *state == StreamerState::Waiting;
+                        if waiting {
+                            *state = StreamerState::Disconnected(Instant::now());
+                        }
+                        waiting
+                    });
+                    lobby.activity.streamer_seen();
+                    lobby.to_viewers(payload);
+                } else {
+                    // Every instance hears every viewer come and go, the reaper counts them all
+                    if let ws::Message::Text(text) = &payload {
+                        if let Ok(presence) = serde_json::from_str::<Presence>(text) {
+                            lobby.activity.announced(&presence);
+                        }
+                    }
+                    if matches!(*lobby.streamer.borrow(), StreamerState::Connected(_)) {
+                        // Only the instance the game is connected to queues messages for it
+                        lobby.activity.viewer_seen();
+                        let _ = lobby.channels.client_to_streamer.try_send(payload);
+                    }
+                }
+            },
+            res = closed.changed() => {
+                if res.is_err() {
+                    return None;
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use rocket::tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use rocket::tokio::net::tcp::OwnedReadHalf;
+    use rocket::tokio::net::{TcpListener, TcpStream};
+    use rocket::tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{Lobby, LobbyOptions};
+
+    /// Keys and subscriptions of the [`stand_in`]
+    #[derive(Default)]
+    struct Store {
+        /// Stored values by key
+        keys: HashMap<Vec<u8>, Vec<u8>>,
+        /// Stored hashes by key
+        hashes: HashMap<Vec<u8>, HashMap<Vec<u8>, Vec<u8>>>,
+        /// When keys with a time to live expire
+        expires: HashMap<Vec<u8>, Instant>,
+        /// Connections subscribed to a channel
+        subscribers: HashMap<Vec<u8>, Vec<mpsc::UnboundedSender<Vec<u8>>>>,
+    }
+
+    /// Start just enough of a Redis for the backend, returns its url
+    ///
+    /// Understands `GET`, `SET` (with `NX` and `EX`), `HSET`, `HGETALL`, `EXPIRE`, `DEL`,
+    /// `PUBLISH` and `SUBSCRIBE`, everything else is an error.
+    pub async fn stand_in() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let store = Arc::new(Mutex::new(Store::default()));
+        rocket::tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                rocket::tokio::spawn(serve(socket, Arc::clone(&store)));
+            }
+        });
+        url
+    }
+
+    /// Answer the commands of one connection
+    async fn serve(socket: TcpStream, store: Arc<Mutex<Store>>) {
+        let (read, mut write) = socket.into_split();
+        let mut read = BufReader::new(read);
+        let (out, mut out_recv) = mpsc::unbounded_channel::<Vec<u8>>();
+        rocket::tokio::spawn(async move {
+            while let Some(bytes) = out_recv.recv().await {
+                if write.write_all(&bytes).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        while let Some(command) = read_command(&mut read).await {
+            let [name, args @ ..] = command.as_slice() else {
+                break;
+            };
+            let mut store = store.lock().unwrap();
+            store.expire();
+            let reply = match (name.to_ascii_uppercase().as_slice(), args) {
+                (b"GET", [key]) => store
+                    .keys
+                    .get(key)
+                    .map_or_else(|| b"$-1\r\n".to_vec(), |value| bulk(value)),
+                (b"SET", [key, value, options @ ..]) => store.set(key, value, options),
+                (b"HSET", [key, field, value]) => {
+                    let hash = store.hashes.entry(key.clone()).or_default();
+                    integer(usize::from(
+                        hash.insert(field.clone(), value.clone()).is_none(),
+                    ))
+                }
+                (b"HGETALL", [key]) => {
+                    let pairs = store.hashes.get(key).into_iter().flatten();
+                    let items = pairs
+                        .flat_map(|(field, value)| [bulk(field), bulk(value)])
+                        .collect::<Vec<_>>();
+                    array(&items)
+                }
+                (b"EXPIRE", [key, seconds]) => {
+                    let kept = store.keys.contains_key(key) || store.hashes.contains_key(key);
+                    if kept {
+                        let expires = Instant::now() + Duration::from_secs(number(seconds));
+                        store.expires.insert(key.clone(), expires);
+                    }
+                    integer(usize::from(kept))
+                }
+                (b"DEL", keys) => {
+                    let mut removed = 0;
+                    for key in keys {
+                        store.expires.remove(key);
+                        let value = store.keys.remove(key).is_some();
+                        let hash = store.hashes.remove(key).is_some();
+                        removed += usize::from(value || hash);
+                    }
+                    integer(removed)
+                }
+                (b"PUBLISH", [channel, payload]) => {
+                    let push = array(&[bulk(b"message"), bulk(channel), bulk(payload)]);
+                    let subscribers = store.subscribers.entry(channel.clone()).or_default();
+                    subscribers.retain(|subscriber| subscriber.send(push.clone()).is_ok());
+                    integer(subscribers.len())
+                }
+                (b"SUBSCRIBE", channels) => {
+                    let mut reply = Vec::new();
+                    for (i, channel) in channels.iter().enumerate() {
+                        store
+                            .subscribers
+                            .entry(channel.clone())
+                            .or_default()
+                            .push(out.clone());
+                        reply.extend(array(&[bulk(b"subscribe"), bulk(channel), integer(i + 1)]));
+                    }
+                    reply
+                }
+                _ => b"-ERR unknown command\r\n".to_vec(),
+            };
+            if out.send(reply).is_err() {
+                break;
+            }
+        }
+    }
+
+    impl Store {
+        /// Forget the keys whose time to live is over
+        fn expire(&mut self) {
+            let now = Instant::now();
+            let expired: Vec<_> = self
+                .expires
+                .iter()
+                .filter(|(_, expires)| **expires <= now)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in expired {
+                self.expires.remove(&key);
+                self.keys.remove(&key);
+                self.hashes.remove(&key);
+            }
+        }
+
+        /// Answer `SET key value` with `options`
+        fn set(&mut self, key: &[u8], value: &[u8], options: &[Vec<u8>]) -> Vec<u8> {
+            let mut ttl = None;
+            let mut only_new = false;
+            let mut options = options.iter();
+            while let Some(option) = options.next() {
+                match option.to_ascii_uppercase().as_slice() {
+                    b"NX" => only_new = true,
+                    b"EX" => ttl = options.next().map(|seconds| number(seconds)),
+                    _ => return b"-ERR syntax error\r\n".to_vec(),
+                }
+            }
+            if only_new && self.keys.contains_key(key) {
+                return b"$-1\r\n".to_vec();
+            }
+            self.keys.insert(key.to_vec(), value.to_vec());
+            match ttl {
+                Some(ttl) => {
+                    let expires = Instant::now() + Duration::from_secs(ttl);
+                    self.expires.insert(key.to_vec(), expires);
+                }
+                None => {
+                    self.expires.remove(key);
+                }
+            }
+            b"+OK\r\n".to_vec()
+        }
+    }
+
+    /// Read a number sent as a bulk string
+    fn number(bytes: &[u8]) -> u64 {
+        String::from_utf8_lossy(bytes).parse().unwrap()
+    }
+
+    /// Read a command, an array of bulk strings
+    async fn read_command(read: &mut BufReader<OwnedReadHalf>) -> Option<Vec<Vec<u8>>> {
+        let count = read_number(read, '*').await?;
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            let len = read_number(read, '$').await?;
+            let mut arg = vec![0; len + 2];
+            read.read_exact(&mut arg).await.ok()?;
+            arg.truncate(len);
+            args.push(arg);
+        }
+        Some(args)
+    }
+
+    /// Read a line holding a number after `prefix`
+    async fn read_number(read: &mut BufReader<OwnedReadHalf>, prefix: char) -> Option<usize> {
+        let mut line = String::new();
+        read.read_line(&mut line).await.ok()?;
+        line.strip_prefix(prefix)?.trim_end().parse().ok()
+    }
+
+    fn bulk(bytes: &[u8]) -> Vec<u8> {
+        let mut reply = format!("${}\r\n", bytes.len()).into_bytes();
+        reply.extend_from_slice(bytes);
+        reply.extend_from_slice(b"\r\n");
+        reply
+    }
+
+    fn integer(n: usize) -> Vec<u8> {
+        format!(":{n}\r\n").into_bytes()
+    }
+
+    fn array(items: &[Vec<u8>]) -> Vec<u8> {
+        let mut reply = format!("*{}\r\n", items.len()).into_bytes();
+        reply.extend(items.concat());
+        reply
+    }
+
+    fn record(owner: &str) -> LobbyRecord {
+        LobbyRecord {
+            owner: owner.into(),
+            streamer_key: "key".into(),
+            created_at: 1_728_000_000,
+            options: LobbyOptions::default(),
+        }
+    }
+
+    #[test]
+    fn encoding() {
+        let text = ws::Message::Text("hi".into());
+        let binary = ws::Message::Binary(vec![0, 1]);
+
+        assert_eq!(decode(&encode(text.clone()).unwrap()), Some(text));
+        assert_eq!(decode(&encode(binary.clone()).unwrap()), Some(binary));
+        assert_eq!(encode(ws::Message::Ping(Vec::new())), None);
+        assert_eq!(decode(b""), None);
+    }
+
+    #[rocket::async_test]
+    async fn shared_lobby() {
+        let url = stand_in().await;
+        let here = RedisBackend::connect(&url, 60).await.unwrap();
+        let there = RedisBackend::connect(&url, 60).await.unwrap();
+
+        here.announce(&record("viv")).await;
+        assert_eq!(there.find("viv").await, Some(record("viv")));
+        assert_eq!(there.find("alice").await, None);
+
+        let lobby = Lobby::restore(record("viv"), Metrics::default());
+        let (mut viewers, _) = lobby.channels.streamer_to_client.subscribe();
+        let delivery = rocket::tokio::spawn(there.attach(lobby.local()).await);
+
+        let sender = Lobby::new(
+            Arc::from("viv"),
+            LobbyOptions::default(),
+            Metrics::default(),
+        );
+        let hello = ws::Message::Text("hello".into());
+        here.send(&sender.local(), Route::Viewers, hello.clone())
+            .await;
+        assert_eq!(viewers.recv().await.unwrap(), hello);
+
+        here.withdraw("viv", "Bye").await;
+        assert_eq!(delivery.await.unwrap().as_deref(), Some("Bye"));
+        assert_eq!(there.find("viv").await, None);
+    }
+
+    #[rocket::async_test]
+    async fn records_expire_unless_refreshed() {
+        let url = stand_in().await;
+        let here = RedisBackend::connect(&url, 1).await.unwrap();
+        let there = RedisBackend::connect(&url, 1).await.unwrap();
+
+        here.announce(&record("viv")).await;
+        here.announce(&record("alice")).await;
+        rocket::tokio::time::sleep(Duration::from_millis(600)).await;
+        here.refresh(&record("viv")).await;
+        rocket::tokio::time::sleep(Duration::from_millis(600)).await;
+
+        assert_eq!(there.find("viv").await, Some(record("viv")));
+        assert_eq!(there.find("alice").await, None);
+
+        // A lobby that was never announced, like a restored one, is announced by the refresh
+        here.refresh(&record("alice")).await;
+        assert_eq!(there.find("alice").await, Some(record("alice")));
+    }
+
+    #[rocket::async_test]
+    async fn refresh_keeps_rotated_key() {
+        let url = stand_in().await;
+        let here = RedisBackend::connect(&url, 60).await.unwrap();
+        let there = RedisBackend::connect(&url, 60).await.unwrap();
+        let rotated = LobbyRecord {
+            streamer_key: "rotated".into(),
+            ..record("viv")
+        };
+
+        here.announce(&record("viv")).await;
+        there.announce(&rotated).await;
+        here.refresh(&record("viv")).await;
+
+        assert_eq!(there.find("viv").await, Some(rotated));
+    }
+
+    #[rocket::async_test]
+    async fn copies_catch_up_on_sticky_messages() {
+        let url = stand_in().await;
+        let here = RedisBackend::connect(&url, 60).await.unwrap();
+        let there = RedisBackend::connect(&url, 60).await.unwrap();
+        let lobby = Lobby::new(
+            Arc::from("viv"),
+            LobbyOptions::default(),
+            Metrics::default(),
+        );
+        let css = |css: &str| ws::Message::Text(format!(r#"{{"data": {{"css": "{css}"}}}}"#));
+        let units = ws::Message::Text(r#"{"data": []}"#.into());
+        for message in [css("old"), units.clone(), css("new")] {
+            here.send(&lobby.local(), Route::Viewers, message).await;
+        }
+        here.send(&lobby.local(), Route::Game, css("game")).await;
+
+        let copy = Lobby::restore(record("viv"), Metrics::default());
+        drop(there.attach(copy.local()).await);
+        let (_, snapshot) = copy.channels.streamer_to_client.subscribe();
+        assert_eq!(snapshot, [units, css("new")]);
+
+        here.withdraw("viv", "Bye").await;
+        assert_eq!(there.snapshot("viv").await, []);
+    }
+}
@@ -0,0 +1,184 @@
+//! Json text and msgpack binary frames
+//!
+//! Every connection chooses the encoding it reads when connecting, messages are converted for
+//! connections that asked for the other one.
+
+use std::io::Cursor;
+use std::sync::{Arc, OnceLock};
+
+use rocket::serde::json::{serde_json, Value};
+use serde::{Deserialize, Serialize};
+
+/// How a connection wants its messages encoded
+#[derive(FromFormField, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Json in text frames, what every client understands
+    #[default]
+    Json,
+    /// Msgpack in binary frames
+    #[field(value = "msgpack")]
+    MessagePack,
+}
+
+impl Encoding {
+    /// Convert `message` into this encoding
+    ///
+    /// Messages that are not json or msgpack, or already in this encoding, are passed on
+    /// untouched.
+    pub fn convert(self, message: ws::Message) -> ws::Message {
+        let converted = match (self, &message) {
+            (Self::Json, ws::Message::Binary(_)) => decode(&message)
+                .and_then(|value| serde_json::to_string(&value).ok())
+                .map(ws::Message::Text),
+            (Self::MessagePack, ws::Message::Text(_)) => decode(&message)
+                .and_then(|value| rmp_serde::to_vec_named(&value).ok())
+                .map(ws::Message::Binary),
+            _ => None,
+        };
+        converted.unwrap_or(message)
+    }
+}
+
+/// A message of the game shared by all viewers, converted at most once for those that asked for
+/// the other encoding
+#[derive(Debug, Clone)]
+pub struct SharedMessage(Arc<Conversions>);
+
+/// The message as the game sent it, and in the other encoding once a viewer wanted it
+#[derive(Debug)]
+struct Conversions {
+    /// What the game sent
+    original: ws::Message,
+    /// The original in the other encoding
+    converted: OnceLock<ws::Message>,
+}
+
+impl SharedMessage {
+    /// The message as the game sent it
+    pub fn original(&self) -> &ws::Message {
+        &self.0.original
+    }
+
+    /// The message in `encoding`, converting it only for the first viewer that needs it
+    pub fn get(&self, encoding: Encoding) -> ws::Message {
+        let original = &self.0.original;
+        match (encoding, original) {
+            (Encoding::Json, ws::Message::Binary(_))
+            | (Encoding::MessagePack, ws::Message::Text(_)) => self
+                .0
+                .converted
+                .get_or_init(|| encoding.convert(original.clone()))
+                .clone(),
+            _ => original.clone(),
+        }
+    }
+}
+
+impl From<ws::Message> for SharedMessage {
+    fn from(original: ws::Message) -> Self {
+        Self(Arc::new(Conversions {
+            original,
+            converted: OnceLock::new(),
+        }))
+    }
+}
+
+impl PartialEq for SharedMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.original() == other.original()
+    }
+}
+
+impl PartialEq<ws::Message> for SharedMessage {
+    fn eq(&self, other: &ws::Message) -> bool {
+        self.original() == other
+    }
+}
+
+/// Read a json text or msgpack binary message
+pub fn decode(message: &ws::Message) -> Option<Value> {
+    match message {
+        ws::Message::Text(text) => serde_json::from_str(text).ok(),
+        ws::Message::Binary(bytes) => {
+            let mut deserializer = rmp_serde::Deserializer::new(Cursor::new(bytes));
+            let value = Value::deserialize(&mut deserializer).ok()?;
+            // Binary data that merely starts like msgpack is not msgpack
+            let whole = usize::try_from(deserializer.position()).ok() == Some(bytes.len());
+            whole.then_some(value)
+        }
+        _ => None,
+    }
+}
+
+/// Encode `value` in the same encoding as `like`
+pub fn encode_like(like: &ws::Message, value: &impl Serialize) -> Option<ws::Message> {
+    if like.is_binary() {
+        rmp_serde::to_vec_named(value).ok().map(ws::Message::Binary)
+    } else {
+        serde_json::to_string(value).ok().map(ws::Message::Text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use rocket::serde::json::json;
+
+    use super::*;
+
+    fn text(text: &str) -> ws::Message {
+        ws::Message::Text(text.into())
+    }
+
+    fn msgpack(value: &Value) -> ws::Message {
+        ws::Message::Binary(rmp_serde::to_vec_named(value).unwrap())
+    }
+
+    #[test]
+    fn convert() {
+        let value = json!({"data": [{"id": 1, "kind": "Sphere", "x": 0.5, "y": 0.25}]});
+        let json = text(&value.to_string());
+
+        assert_eq!(Encoding::MessagePack.convert(json.clone()), msgpack(&value));
+        assert_eq!(Encoding::Json.convert(msgpack(&value)), json);
+        assert_eq!(Encoding::Json.convert(json.clone()), json);
+        assert_eq!(
+            Encoding::MessagePack.convert(msgpack(&value)),
+            msgpack(&value)
+        );
+    }
+
+    #[test]
+    fn shared() {
+        let value = json!({"data": []});
+        let shared = SharedMessage::from(text(&value.to_string()));
+
+        assert_eq!(shared.get(Encoding::Json), text(&value.to_string()));
+        assert_eq!(shared.get(Encoding::MessagePack), msgpack(&value));
+        assert!(shared.0.converted.get().is_some());
+        assert_eq!(shared.clone().get(Encoding::MessagePack), msgpack(&value));
+        assert_eq!(shared, text(&value.to_string()));
+    }
+
+    #[test]
+    fn untouched() {
+        let raw = ws::Message::Binary(vec![1, 2, 3]);
+        assert_eq!(decode(&raw), None);
+        assert_eq!(Encoding::Json.convert(raw.clone()), raw);
+        assert_eq!(Encoding::MessagePack.convert(text("hello")), text("hello"));
+    }
+
+    #[test]
+    fn same_encoding() {
+        let value = json!({"error": "rate_limited"});
+        assert_eq!(
+            encode_like(&text(""), &value),
+            Some(text(&value.to_string()))
+        );
+        assert_eq!(
+            encode_like(&ws::Message::Binary(Vec::new()), &value),
+            Some(msgpack(&value))
+        );
+    }
+}
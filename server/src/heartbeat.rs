@@ -0,0 +1,133 @@
+//! Websocket pings to find connections that died without closing
+
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use rocket::fairing::AdHoc;
+use rocket::tokio::time::{self, Interval, MissedTickBehavior};
+use serde::Deserialize;
+
+/// How often connections are pinged, all values are in seconds
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    /// Time between pings
+    pub ping_interval: u64,
+    /// Connections that have not sent anything, not even a pong, for this long are closed
+    pub ping_timeout: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: 20,
+            ping_timeout: 60,
+        }
+    }
+}
+
+/// Manages the [`HeartbeatConfig`]
+pub fn fairing() -> AdHoc {
+    AdHoc::config::<HeartbeatConfig>()
+}
+
+/// What the connection should do next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Beat {
+    /// Send a ping
+    Ping,
+    /// The other side has been silent for too long, close the connection
+    Dead,
+}
+
+/// When the other side of a connection was last heard from
+#[derive(Debug, Clone)]
+pub struct LastSeen(Arc<Mutex<Instant>>);
+
+impl LastSeen {
+    /// The other side sent something
+    pub fn seen(&self) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = Instant::now();
+    }
+
+    /// How long the other side has been silent at `now`
+    fn silent(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(*self.0.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+/// Pings a connection and notices when it stops answering
+#[derive(Debug)]
+pub struct Heartbeat {
+    /// Ticks when the next ping is due
+    interval: Interval,
+    /// How long the other side may stay silent
+    timeout: Duration,
+    /// When the other side was last heard from
+    last_seen: LastSeen,
+}
+
+impl Heartbeat {
+    /// Start the heartbeat of a new connection
+    pub fn new(config: HeartbeatConfig) -> Self {
+        let period = Duration::from_secs(config.ping_interval.max(1));
+        let mut interval = time::interval_at(time::Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            interval,
+            timeout: Duration::from_secs(config.ping_timeout.max(1)),
+            last_seen: LastSeen(Arc::new(Mutex::new(Instant::now()))),
+        }
+    }
+
+    /// Handle to mark the other side as alive, any message counts
+    pub fn last_seen(&self) -> LastSeen {
+        self.last_seen.clone()
+    }
+
+    /// Whether the other side is still alive at `now`
+    fn beat(&self, now: Instant) -> Beat {
+        if self.last_seen.silent(now) >= self.timeout {
+            Beat::Dead
+        } else {
+            Beat::Ping
+        }
+    }
+
+    /// Wait for the next ping to be due
+    pub async fn tick(&mut self) -> Beat {
+        self.interval.tick().await;
+        self.beat(Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+
+    const CONFIG: HeartbeatConfig = HeartbeatConfig {
+        ping_interval: 10,
+        ping_timeout: 25,
+    };
+
+    #[rocket::async_test]
+    async fn silent() {
+        let heartbeat = Heartbeat::new(CONFIG);
+        let now = Instant::now();
+
+        assert_eq!(heartbeat.beat(now + Duration::from_secs(24)), Beat::Ping);
+        assert_eq!(heartbeat.beat(now + Duration::from_secs(25)), Beat::Dead);
+    }
+
+    #[rocket::async_test]
+    async fn seen() {
+        let heartbeat = Heartbeat::new(CONFIG);
+        let later = Instant::now() + Duration::from_secs(30);
+        assert_eq!(heartbeat.beat(later), Beat::Dead);
+
+        *heartbeat.last_seen.0.lock().unwrap() = Instant::now() + Duration::from_secs(20);
+        assert_eq!(heartbeat.beat(later), Beat::Ping);
+    }
+}
@@ -38,32 +38,181 @@
 
 #[macro_use]
 extern crate rocket;
+
+mod admin;
+mod auth;
+mod backend;
+mod encoding;
+mod heartbeat;
+mod metrics;
+mod protocol;
+mod public_url;
+mod rate_limit;
+mod reaper;
+mod registry;
+mod sticky;
+mod streamer;
+mod targeted;
+mod viewer;
+
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-use std::time::Instant;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use log::{error, warn};
-use rocket::futures::{SinkExt, StreamExt};
 use rocket::http::Status;
 use rocket::response::status;
-use rocket::serde::json::Json;
+use rocket::serde::json::{Json, Value};
 use rocket::tokio::sync;
 use rocket::{Request, State};
 use serde::{Deserialize, Serialize};
+use twitch_minimap_protocol::{GameState, LobbyStatus, Presence, StreamerLogin, ViewerMessage};
 
-/// The host we are at
-const HOST: &str = "localhost:8000";
+use crate::auth::{Role, TokenVerifier, ViewerIdentity};
+use crate::backend::{InProcess, LobbyBackend, LobbyLink, LocalLobby};
+use crate::encoding::Encoding;
+use crate::heartbeat::HeartbeatConfig;
+use crate::metrics::Metrics;
+use crate::protocol::{self as declared, Declaration};
+use crate::public_url::PublicUrl;
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::registry::{LobbyRecord, Registry};
+use crate::sticky::StickyBroadcast;
+use crate::streamer::StreamerSession;
+use crate::targeted::ViewerDirectory;
+use crate::viewer::{LagPolicy, ViewerSession};
 
 /// Twitch user id
 type UserId = Arc<str>;
 
 /// Holds the communication channels for proxying events between the streamer and the clients
-#[derive(Debug)]
+///
+/// These live as long as the lobby, so viewers stay attached while the streamer reconnects.
+#[derive(Debug, Clone)]
 struct LobbyChannels {
     /// Client --> Streamer
     client_to_streamer: sync::mpsc::Sender<ws::Message>,
+    /// Receiving end of `client_to_streamer`, locked by the connected streamer
+    streamer_inbox: Arc<sync::Mutex<sync::mpsc::Receiver<ws::Message>>>,
     /// Streamer --> Client
-    streamer_to_client: sync::broadcast::Receiver<ws::Message>,
+    streamer_to_client: StickyBroadcast,
+    /// Streamer --> one Client
+    viewers: ViewerDirectory,
+}
+
+impl LobbyChannels {
+    /// Create the channels for a new lobby
+    fn new() -> Self {
+        let (client_to_streamer, streamer_inbox) = sync::mpsc::channel(100);
+        Self {
+            client_to_streamer,
+            streamer_inbox: Arc::new(sync::Mutex::new(streamer_inbox)),
+            streamer_to_client: StickyBroadcast::new(100),
+            viewers: ViewerDirectory::default(),
+        }
+    }
+}
+
+/// Whether the game is attached to a lobby
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamerState {
+    /// The game has not connected yet
+    Waiting,
+    /// The game is connected, the id identifies the websocket connection
+    Connected(uuid::Uuid),
+    /// The game disconnected at the given time, it can resume until the grace period is over
+    Disconnected(Instant),
+}
+
+/// Snapshot of when a lobby was last used
+#[derive(Debug, Clone, Copy)]
+struct Activity {
+    /// Last time the streamer connected or sent a message
+    streamer_seen: Instant,
+    /// Number of viewers connected to this instance
+    viewers: usize,
+    /// Number of viewers announced through a shared lobby backend, on any instance
+    announced_viewers: usize,
+    /// Last time a viewer connected or disconnected
+    viewers_seen: Instant,
+}
+
+/// Shared handle to the [`Activity`] of a lobby, updated by the connection tasks
+#[derive(Debug, Clone)]
+struct ActivityTracker(Arc<Mutex<Activity>>);
+
+impl ActivityTracker {
+    /// Start tracking from `now`
+    fn new(now: Instant) -> Self {
+        Self(Arc::new(Mutex::new(Activity {
+            streamer_seen: now,
+            viewers: 0,
+            announced_viewers: 0,
+            viewers_seen: now,
+        })))
+    }
+
+    /// Get the current activity
+    fn get(&self) -> Option<Activity> {
+        self.0.lock().ok().map(|activity| *activity)
+    }
+
+    /// Run `f` on the activity, if the lock is poisoned the update is lost
+    fn update(&self, f: impl FnOnce(&mut Activity)) {
+        if let Ok(mut activity) = self.0.lock() {
+            f(&mut activity);
+        }
+    }
+
+    /// The streamer did something
+    fn streamer_seen(&self) {
+        self.update(|activity| activity.streamer_seen = Instant::now());
+    }
+
+    /// A viewer on another instance sent something
+    fn viewer_seen(&self) {
+        self.update(|activity| activity.viewers_seen = Instant::now());
+    }
+
+    /// A viewer connected
+    fn viewer_joined(&self) {
+        self.update(|activity| {
+            activity.viewers += 1;
+            activity.viewers_seen = Instant::now();
+        });
+    }
+
+    /// A viewer disconnected
+    fn viewer_left(&self) {
+        self.update(|activity| {
+            activity.viewers = activity.viewers.saturating_sub(1);
+            activity.viewers_seen = Instant::now();
+        });
+    }
+
+    /// A viewer connected to or disconnected from some instance sharing the lobby
+    fn announced(&self, presence: &Presence) {
+        self.update(|activity| {
+            activity.announced_viewers = match presence {
+                Presence::ViewerJoined { .. } => activity.announced_viewers + 1,
+                Presence::ViewerLeft { .. } => activity.announced_viewers.saturating_sub(1),
+            };
+            activity.viewers_seen = Instant::now();
+        });
+    }
+}
+
+/// Settings the game can choose when creating a lobby
+#[derive(FromForm, Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+struct LobbyOptions {
+    /// What to do with viewers that fall behind
+    #[field(default_with = Some(LagPolicy::Resync))]
+    lag: LagPolicy,
+    /// How many messages viewers may send
+    limit: RateLimit,
+    /// Messages the game speaks, lobbies stored before it was declared speak the first version
+    #[serde(default)]
+    protocol: Declaration,
 }
 
 /// A lobby is one instance of a game, one per channel
@@ -73,26 +222,316 @@ struct Lobby {
     owner: UserId,
     /// Key required for the streamer to connect to the socket
     streamer_key: String,
+    /// When the lobby was created
+    created: Instant,
+    /// When the lobby was created, as wall clock time for reporting
+    created_at: SystemTime,
     /// Channels for communication
-    channels: RwLock<Option<LobbyChannels>>,
+    channels: LobbyChannels,
+    /// Connection state of the streamer, a new connection replaces the old one
+    streamer: sync::watch::Sender<StreamerState>,
+    /// Tracks when the lobby was last used
+    activity: ActivityTracker,
+    /// Set to the reason once the lobby is closed, connection tasks watch this to shut down
+    closed: sync::watch::Sender<Option<String>>,
+    /// Settings chosen by the game
+    options: LobbyOptions,
+    /// Rate limits of the viewers
+    limiter: RateLimiter,
+    /// Metrics the lobby reports to
+    metrics: Metrics,
+    /// Copy of a lobby created on another instance, reaping it only removes the copy
+    mirror: bool,
+    /// Lost the race to another lobby of the same owner, it was never open
+    discarded: bool,
 }
 
 impl Lobby {
     /// Create a new lobby
-    fn new(owner: UserId) -> Self {
+    fn new(owner: UserId, options: LobbyOptions, metrics: Metrics) -> Self {
+        let created = Instant::now();
+        metrics.lobby_opened();
         Self {
             owner,
             streamer_key: uuid::Uuid::new_v4().to_string(),
-            channels: RwLock::new(None),
+            created,
+            created_at: SystemTime::now(),
+            channels: LobbyChannels::new(),
+            streamer: sync::watch::channel(StreamerState::Waiting).0,
+            activity: ActivityTracker::new(created),
+            closed: sync::watch::channel(None).0,
+            limiter: RateLimiter::new(options.limit),
+            options,
+            metrics,
+            mirror: false,
+            discarded: false,
+        }
+    }
+
+    /// Bring back a stored lobby, as if its streamer just disconnected
+    fn restore(record: LobbyRecord, metrics: Metrics) -> Self {
+        let created_at = UNIX_EPOCH + Duration::from_secs(record.created_at);
+        let age = SystemTime::now()
+            .duration_since(created_at)
+            .unwrap_or_default();
+
+        let mut lobby = Self::new(Arc::from(record.owner), record.options, metrics);
+        lobby.streamer_key = record.streamer_key;
+        lobby.created = Instant::now().checked_sub(age).unwrap_or(lobby.created);
+        lobby.created_at = created_at;
+        lobby
+            .streamer
+            .send_replace(StreamerState::Disconnected(Instant::now()));
+        lobby
+    }
+
+    /// What has to be stored to restore the lobby
+    fn record(&self) -> LobbyRecord {
+        LobbyRecord {
+            owner: self.owner.to_string(),
+            streamer_key: self.streamer_key.clone(),
+            created_at: self.status().created_at,
+            options: self.options.clone(),
+        }
+    }
+
+    /// The parts of the lobby the backend delivers messages to
+    fn local(&self) -> LocalLobby {
+        LocalLobby {
+            owner: Arc::clone(&self.owner),
+            channels: self.channels.clone(),
+            streamer: self.streamer.clone(),
+            activity: self.activity.clone(),
+            closed: self.closed.subscribe(),
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    /// Tell all connections of this lobby to shut down
+    fn close(&self, reason: impl Into<String>) {
+        self.closed.send_replace(Some(reason.into()));
+    }
+
+    /// Public status of the lobby
+    fn status(&self) -> LobbyStatus {
+        LobbyStatus {
+            game: match *self.streamer.borrow() {
+                StreamerState::Waiting => GameState::Waiting,
+                StreamerState::Connected(_) => GameState::Connected,
+                StreamerState::Disconnected(_) => GameState::Disconnected,
+            },
+            viewers: self.activity.get().map_or(0, |activity| activity.viewers),
+            created_at: self
+                .created_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |created| created.as_secs()),
+            protocol: self.options.protocol.protocol(),
+        }
+    }
+
+    /// Check that the caller may manage this lobby
+    ///
+    /// Either the current streamer key or an extension token of the broadcaster is accepted.
+    fn authorize(
+        &self,
+        key: Option<&str>,
+        token: Option<&str>,
+        verifier: &TokenVerifier,
+    ) -> Result<(), Errors> {
+        if let Some(key) = key {
+            if key != self.streamer_key {
+                log::warn!("Wrong key provided for lobby of {}", self.owner);
+                return Err(Errors::NotAllowed("Wrong key!".into()));
+            }
+            return Ok(());
+        }
+
+        let Some(token) = token else {
+            return Err(Errors::Unauthorized("Missing key or token".into()));
+        };
+        if verifier.verify(token, &self.owner)?.role != Role::Broadcaster {
+            return Err(Errors::NotAllowed(
+                "Only the broadcaster can manage the lobby".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Lobby {
+    fn drop(&mut self) {
+        if self.discarded {
+            self.metrics.lobby_discarded();
+        } else {
+            self.metrics.lobby_closed(self.created.elapsed());
         }
     }
 }
 
+/// Close frame telling the other side why the connection ends, `code` tells programs apart from
+/// the reason for people
+fn close_frame(code: u16, reason: String) -> ws::Message {
+    ws::Message::Close(Some(ws::frame::CloseFrame {
+        code: code.into(),
+        reason: reason.into(),
+    }))
+}
+
 /// Holds information on the lobbies
-#[derive(Default)]
+#[derive(Clone)]
 struct Lobbies {
     /// Lookup from userid to lobby
-    channels: RwLock<HashMap<UserId, Lobby>>,
+    channels: Arc<RwLock<HashMap<UserId, Lobby>>>,
+    /// Metrics shared by all lobbies
+    metrics: Metrics,
+    /// Stored copy of the lobbies, to restore them after a restart
+    registry: Registry,
+    /// Shares the lobbies with other instances
+    backend: Arc<dyn LobbyBackend>,
+}
+
+impl Default for Lobbies {
+    fn default() -> Self {
+        Self {
+            channels: Arc::default(),
+            metrics: Metrics::default(),
+            registry: Registry::default(),
+            backend: Arc::new(InProcess),
+        }
+    }
+}
+
+impl Lobbies {
+    /// Restore the lobbies stored in `registry`, shared through `backend`
+    async fn restore(registry: Registry, backend: Arc<dyn LobbyBackend>) -> Self {
+        let lobbies = Self {
+            registry,
+            backend,
+            ..Self::default()
+        };
+        let records = lobbies.registry.load();
+        if !records.is_empty() {
+            log::info!("Restoring {} stored lobbies", records.len());
+        }
+        for record in records {
+            let lobby = Lobby::restore(record, lobbies.metrics.clone());
+            if let Err(err) = lobbies.insert(lobby).await {
+                log::error!("Could not restore lobby: {err:?}");
+            }
+        }
+        lobbies
+    }
+
+    /// Open a new lobby for `user` and return its streamer key
+    async fn create(&self, user: &str, options: LobbyOptions) -> Result<String, Errors> {
+        let exists = || {
+            Errors::LobbyAlreadyExsists("Lobby already in play, please close exsisting game instance or wait for previous lobby to timeout".into())
+        };
+        let here = self.channels.read().unknown()?.contains_key(user);
+        if here || self.backend.find(user).await.is_some() {
+            log::warn!("Somebody tried to create a new lobby that already exsists.");
+            return Err(exists());
+        }
+
+        let lobby = Lobby::new(Arc::from(user), options, self.metrics.clone());
+        let key = lobby.streamer_key.clone();
+        let record = lobby.record();
+        self.insert(lobby).await.map_err(|_| exists())?;
+        self.registry.save(&record);
+        self.backend.announce(&record).await;
+        Ok(key)
+    }
+
+    /// Add `lobby` unless its owner already has one
+    ///
+    /// The lobby is attached to the backend before it is listed, so it gets every message sent
+    /// to it once connections can find it.
+    async fn insert(&self, mut lobby: Lobby) -> Result<(), Errors> {
+        let delivery = self.backend.attach(lobby.local()).await;
+        let owner = Arc::clone(&lobby.owner);
+        let closed = lobby.closed.subscribe();
+        match self.channels.write().unknown()?.entry(Arc::clone(&owner)) {
+            Entry::Occupied(_) => {
+                lobby.discarded = true;
+                return Err(Errors::LobbyAlreadyExsists("Lobby already exsists".into()));
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(lobby);
+            }
+        }
+
+        let lobbies = self.clone();
+        rocket::tokio::spawn(async move {
+            if let Some(reason) = delivery.await {
+                lobbies.closed_elsewhere(&owner, &closed, reason);
+            }
+        });
+        Ok(())
+    }
+
+    /// Copy the lobby of `user` from another instance, if there is no current copy here
+    ///
+    /// With a `key` a local copy with a different key counts as outdated, the key may have been
+    /// rotated on another instance.
+    async fn mirror(&self, user: &str, key: Option<&str>) {
+        let current = self.channels.read().ok().and_then(|channels| {
+            let lobby = channels.get(user)?;
+            Some(key.is_none_or(|key| key == lobby.streamer_key))
+        });
+        if current == Some(true) {
+            return;
+        }
+        let Some(record) = self.backend.find(user).await else {
+            return;
+        };
+
+        if current.is_some() {
+            if let Ok(mut channels) = self.channels.write() {
+                if let Some(lobby) = channels.get_mut(user) {
+                    lobby.streamer_key = record.streamer_key;
+                }
+            }
+            return;
+        }
+        let mut lobby = Lobby::restore(record, self.metrics.clone());
+        lobby.mirror = true;
+        // Another connection may have copied it in the meantime, that copy is just as good
+        let _ = self.insert(lobby).await;
+    }
+
+    /// Close the lobby of `user` on every instance
+    async fn close(&self, user: &str, reason: &str) {
+        let removed = self
+            .channels
+            .write()
+            .ok()
+            .and_then(|mut channels| channels.remove(user));
+        if let Some(lobby) = removed {
+            self.registry.remove(user);
+            lobby.close(reason);
+        }
+        self.backend.withdraw(user, reason).await;
+    }
+
+    /// Remove the lobby of `owner` after another instance closed it, unless it was replaced
+    fn closed_elsewhere(
+        &self,
+        owner: &str,
+        closed: &sync::watch::Receiver<Option<String>>,
+        reason: String,
+    ) {
+        let Ok(mut channels) = self.channels.write() else {
+            return;
+        };
+        let same = channels
+            .get(owner)
+            .is_some_and(|lobby| lobby.closed.subscribe().same_channel(closed));
+        if let Some(lobby) = same.then(|| channels.remove(owner)).flatten() {
+            self.registry.remove(owner);
+            log::info!("Lobby of {owner} was closed on another instance");
+            lobby.close(reason);
+        }
+    }
 }
 
 /// Errors that can happen in the api
@@ -104,9 +543,12 @@ enum Errors {
     /// Not allowed
     #[response(status = 403)]
     NotAllowed(String),
-    /// Another socket is already connected
-    #[response(status = 409)]
-    AlreadyConnected(String),
+    /// Missing or invalid token
+    #[response(status = 401)]
+    Unauthorized(String),
+    /// The token was valid, but has expired
+    #[response(status = 401)]
+    TokenExpired(String),
     /// Already Exsists
     #[response(status = 409)]
     LobbyAlreadyExsists(String),
@@ -117,8 +559,6 @@ enum Errors {
 
 /// Extension for Result for convenient shit
 trait ResultExt<T, E> {
-    /// Unknwon with custom msg
-    fn unknown_with(self, msg: impl Into<String>) -> Result<T, Errors>;
     /// Use errors `Debug` as message
     fn unknown(self) -> Result<T, Errors>
     where
@@ -126,12 +566,6 @@ trait ResultExt<T, E> {
 }
 
 impl<T, E> ResultExt<T, E> for Result<T, E> {
-    fn unknown_with(self, msg: impl Into<String>) -> Result<T, Errors> {
-        match self {
-            Ok(val) => Ok(val),
-            Err(_) => Err(Errors::Unknown(msg.into())),
-        }
-    }
     fn unknown(self) -> Result<T, Errors>
     where
         E: std::fmt::Debug,
@@ -152,6 +586,7 @@ struct GenericError {
     reason: Option<String>,
 }
 
+/// Return errors as json instead of rockets default html page
 #[catch(default)]
 fn default_catcher(status: Status, _request: &Request) -> Json<GenericError> {
     Json(GenericError {
@@ -160,6 +595,23 @@ fn default_catcher(status: Status, _request: &Request) -> Json<GenericError> {
     })
 }
 
+/// Wrap a message from a viewer so the game knows who sent it
+///
+/// Json is embedded as is, any other text is embedded as a string. Msgpack is embedded too,
+/// and the wrapped message stays msgpack.
+/// Returns `None` for messages that should not be forwarded.
+fn stamp_viewer_message(viewer: &ViewerIdentity, message: &ws::Message) -> Option<ws::Message> {
+    let data = match message {
+        ws::Message::Text(text) => {
+            encoding::decode(message).unwrap_or_else(|| Value::String(text.clone()))
+        }
+        ws::Message::Binary(_) => encoding::decode(message)?,
+        _ => return None,
+    };
+    let viewer = viewer.clone();
+    encoding::encode_like(message, &ViewerMessage { viewer, data })
+}
+
 /// Return a simple message to show we are working
 #[get("/")]
 const fn index() -> &'static str {
@@ -168,35 +620,116 @@ const fn index() -> &'static str {
 
 /// Create a new lobby for the specifed user
 ///
-/// Returns a key to be used when connecting to `/lobby/connect/streamer`
-#[post("/lobby/new?<user>")]
-fn new_lobby(user: &str, lobbies: &State<Lobbies>) -> Result<status::Created<String>, Errors> {
-    let user = Arc::from(user);
-    let mut channels = lobbies.channels.write().unknown()?;
+/// Returns the key and url to connect to `/lobby/connect/streamer` with
+#[post("/lobby/new?<user>&<options..>")]
+async fn new_lobby(
+    user: &str,
+    options: LobbyOptions,
+    lobbies: &State<Lobbies>,
+    public_url: &State<PublicUrl>,
+) -> Result<status::Created<Json<StreamerLogin>>, Errors> {
+    let key = lobbies.create(user, options).await?;
+    let login = public_url.login(user, key);
+    Ok(status::Created::new(login.url.clone()).body(Json(login)))
+}
+
+/// Get the status of the lobby of the specified user
+///
+/// This is public, extensions can use it to find out if the game is running before connecting.
+#[get("/lobby/status?<user>")]
+async fn lobby_status(user: &str, lobbies: &State<Lobbies>) -> Result<Json<LobbyStatus>, Errors> {
+    lobbies.mirror(user, None).await;
+    let channels = lobbies.channels.read().unknown()?;
+    let Some(lobby) = channels.get(user) else {
+        return Err(Errors::NotFound("Lobby does not exsit".into()));
+    };
+    Ok(Json(lobby.status()))
+}
 
-    if channels.contains_key(&user) {
-        log::warn!("Somebody tried to create a new lobby that already exsists.");
-        return Err(Errors::LobbyAlreadyExsists("Lobby already in play, please close exsisting game instance or wait for previous lobby to timeout".into()));
+/// Close the lobby of the specified user
+///
+/// Needs either the streamer `key` or an extension `token` of the broadcaster.
+/// All connections are closed with a close frame.
+#[delete("/lobby?<user>&<key>&<token>")]
+async fn close_lobby(
+    user: &str,
+    key: Option<&str>,
+    token: Option<&str>,
+    lobbies: &State<Lobbies>,
+    verifier: &State<TokenVerifier>,
+) -> Result<status::NoContent, Errors> {
+    lobbies.mirror(user, key).await;
+    {
+        let channels = lobbies.channels.read().unknown()?;
+        let Some(lobby) = channels.get(user) else {
+            return Err(Errors::NotFound("Lobby does not exsit".into()));
+        };
+        lobby.authorize(key, token, verifier)?;
     }
 
-    let lobby = Lobby::new(Arc::clone(&user));
-    let key = lobby.streamer_key.clone();
-    channels.insert(Arc::clone(&user), lobby);
+    log::info!("Lobby of {user} was closed");
+    lobbies
+        .close(user, "Lobby was closed by the streamer")
+        .await;
+    Ok(status::NoContent)
+}
+
+/// Replace the streamer key of the lobby of the specified user
+///
+/// Needs either the current streamer `key` or an extension `token` of the broadcaster.
+/// The connected streamer is disconnected and has to resume with the returned key, viewers stay
+/// connected.
+#[post("/lobby/rotate-key?<user>&<key>&<token>")]
+async fn rotate_key(
+    user: &str,
+    key: Option<&str>,
+    token: Option<&str>,
+    lobbies: &State<Lobbies>,
+    verifier: &State<TokenVerifier>,
+    public_url: &State<PublicUrl>,
+) -> Result<Json<StreamerLogin>, Errors> {
+    lobbies.mirror(user, key).await;
+    let record = {
+        let mut channels = lobbies.channels.write().unknown()?;
+        let Some(lobby) = channels.get_mut(user) else {
+            return Err(Errors::NotFound("Lobby does not exsit".into()));
+        };
+        lobby.authorize(key, token, verifier)?;
 
-    Ok(status::Created::new(format!(
-        "ws://{HOST}/lobby/connect/streamer?user={user}&key={key}"
-    ))
-    .body(key))
+        lobby.streamer_key = uuid::Uuid::new_v4().to_string();
+        lobby.streamer.send_if_modified(|state| {
+            let connected = matches!(state, StreamerState::Connected(_));
+            if connected {
+                *state = StreamerState::Disconnected(Instant::now());
+            }
+            connected
+        });
+        lobby.record()
+    };
+    lobbies.registry.save(&record);
+    lobbies.backend.announce(&record).await;
+    log::info!("Rotated streamer key of lobby of {user}");
+
+    Ok(Json(public_url.login(user, record.streamer_key)))
 }
 
 /// Connect to the lobby as a streamer
-#[get("/lobby/connect/streamer?<user>&<key>")]
-fn connect_streamer<'a>(
+///
+/// Connecting again with the same key resumes the lobby, viewers stay connected in the meantime.
+/// If the previous connection is still open it is replaced.
+/// `encoding` is what the game wants to receive, json unless set to `msgpack`. If `protocol` is
+/// not the version declared when creating the lobby the connection is closed right away.
+#[get("/lobby/connect/streamer?<user>&<key>&<encoding>&<protocol>")]
+async fn connect_streamer(
     ws: ws::WebSocket,
-    user: &'a str,
+    user: &str,
     key: &str,
-    lobbies: &'a State<Lobbies>,
-) -> Result<ws::Channel<'a>, Errors> {
+    encoding: Option<Encoding>,
+    protocol: Option<u32>,
+    lobbies: &State<Lobbies>,
+    heartbeat: &State<HeartbeatConfig>,
+) -> Result<ws::Channel<'static>, Errors> {
+    lobbies.mirror(user, Some(key)).await;
     let channels = lobbies.channels.read().unknown()?;
     let Some(lobby) = channels.get(user) else {
         log::warn!("Streamer tried to connect to unknown lobby.");
@@ -210,134 +743,100 @@ fn connect_streamer<'a>(
         );
         return Err(Errors::NotAllowed("Wrong key!".into()));
     }
-
-    let mut lobby_channels = lobby.channels.write().unknown()?;
-    if lobby_channels.is_some() {
-        log::warn!("Streamer tried to connect but lobby already exsits.");
-        return Err(Errors::AlreadyConnected(
-            "You are already connected to this lobby".to_owned(),
-        ));
+    if let Some(reason) = lobby.options.protocol.mismatch(protocol) {
+        log::warn!("Streamer of {} speaks another protocol", lobby.owner);
+        return Ok(declared::refuse(ws, reason));
     }
 
-    let (streamer_to_client_send, streamer_to_client_recv) = sync::broadcast::channel(100);
-    let (client_to_streamer_send, client_to_streamer_recv) = sync::mpsc::channel(100);
-
-    *lobby_channels = Some(LobbyChannels {
-        streamer_to_client: streamer_to_client_recv,
-        client_to_streamer: client_to_streamer_send,
-    });
+    let session = StreamerSession {
+        user: Arc::clone(&lobby.owner),
+        connection_id: uuid::Uuid::new_v4(),
+        inbox: Arc::clone(&lobby.channels.streamer_inbox),
+        to_viewers: LobbyLink::new(Arc::clone(&lobbies.backend), lobby.local()),
+        streamer: lobby.streamer.clone(),
+        activity: lobby.activity.clone(),
+        closed: lobby.closed.subscribe(),
+        metrics: lobby.metrics.clone(),
+        heartbeat: *heartbeat.inner(),
+        encoding: encoding.unwrap_or_default(),
+    };
+    lobby.activity.streamer_seen();
 
     // Make sure we dont hold the locks too long
-    drop(lobby_channels);
     drop(channels);
 
-    Ok(ws.channel(move |mut connection| {
-        Box::pin(async move {
-            let mut channel_recv = client_to_streamer_recv;
-            let channel_send = streamer_to_client_send;
-
-            loop {
-                rocket::tokio::select! {
-                    res = connection.next() => {
-                        if let Some(Ok(message)) = res {
-                            if !message.is_close() {
-                                let _ = channel_send.send(message);
-                            }
-                        } else {
-                            info!("STREAM: Websocket closed");
-                            break;
-                        }
-                    },
-                    res = channel_recv.recv() => {
-                        if let Some(message) = res {
-                            let _ = connection.send(message).await;
-                        }
-                    },
-                }
-            }
-
-            if let Ok(mut channels) = lobbies.channels.write() {
-                log::info!("Closing lobby");
-                channels.remove(user);
-            }
-
-            Ok(())
-        })
-    }))
+    Ok(ws.channel(move |connection| Box::pin(session.run(connection))))
 }
 
 /// Connect to the lobby
-#[get("/lobby/connect?<user>")]
-fn connect_user(
+///
+/// `token` is the twitch extension JWT, it has to be signed with the extension secret and issued
+/// for the channel `user`. `encoding` is what the viewer wants to receive, json unless set to
+/// `msgpack`. The first message tells the viewer which protocol the game speaks, if `protocol` is
+/// another version the connection is closed right away.
+#[get("/lobby/connect?<user>&<token>&<encoding>&<protocol>")]
+#[allow(clippy::too_many_arguments)] // Rocket passes every query parameter and guard separately
+async fn connect_user(
     ws: ws::WebSocket,
     user: &str,
+    token: Option<&str>,
+    encoding: Option<Encoding>,
+    protocol: Option<u32>,
     lobbies: &State<Lobbies>,
+    verifier: &State<TokenVerifier>,
+    heartbeat: &State<HeartbeatConfig>,
 ) -> Result<ws::Channel<'static>, Errors> {
+    let Some(token) = token else {
+        log::warn!("Viewer tried to connect without a token.");
+        return Err(Errors::Unauthorized("Missing token".into()));
+    };
+    let viewer = verifier.verify(token, user)?.into_viewer();
+
+    lobbies.mirror(user, None).await;
     let channels = lobbies.channels.read().unknown()?;
     let Some(lobby) = channels.get(user) else {
         log::warn!("Viewer tried to connect to unknown lobby.");
         return Err(Errors::NotFound("Lobby does not exsit".into()));
     };
 
-    let lobby_channels_lock = lobby.channels.read().unknown()?;
-    let Some(lobby_channels) = lobby_channels_lock.as_ref() else {
+    if *lobby.streamer.borrow() == StreamerState::Waiting {
         log::warn!("Viewer tried to lobby a that doesnt have a streamer connected yet.");
         return Err(Errors::NotFound(
             "The game has not yet connected to this lobby".to_owned(),
         ));
-    };
+    }
+    if let Some(reason) = lobby.options.protocol.mismatch(protocol) {
+        log::info!("Viewer of {} speaks another protocol", lobby.owner);
+        return Ok(declared::refuse(ws, reason));
+    }
 
-    let mut channel_recv = lobby_channels.streamer_to_client.resubscribe();
-    let channel_send = lobby_channels.client_to_streamer.clone();
+    let session = ViewerSession {
+        viewer,
+        to_game: LobbyLink::new(Arc::clone(&lobbies.backend), lobby.local()),
+        from_game: lobby.channels.streamer_to_client.clone(),
+        directory: lobby.channels.viewers.clone(),
+        lag_policy: lobby.options.lag,
+        protocol: lobby.options.protocol.clone(),
+        encoding: encoding.unwrap_or_default(),
+        limiter: lobby.limiter.clone(),
+        heartbeat: *heartbeat.inner(),
+        closed: lobby.closed.subscribe(),
+        metrics: lobby.metrics.clone(),
+    };
+    let activity = lobby.activity.clone();
+    let metrics = lobby.metrics.clone();
 
     // Make sure we dont hold the locks too long
-    drop(lobby_channels_lock);
     drop(channels);
 
     Ok(ws.channel(move |connection| {
         Box::pin(async move {
-            let (mut connection_send, mut connection_recv) = connection.split();
-
-            let client_stream = async move {
-                // We can only create a instant from the current time,
-                // because its a moment in time and not a timestamp (does that make sense?)
-                let mut is_first_message = true;
-                let mut last_accepted_message_time = Instant::now();
-
-                while let Some(Ok(message)) = connection_recv.next().await {
-                    if !message.is_close() {
-                        if message.len() >= 1000 {
-                            warn!("Client sent message of length {}", message.len());
-                            continue;
-                        }
-
-                        if is_first_message
-                            || Instant::now()
-                                .saturating_duration_since(last_accepted_message_time)
-                                .as_millis()
-                                >= 20
-                        {
-                            is_first_message = false;
-                            last_accepted_message_time = Instant::now();
-                            let _ = channel_send.send(message).await;
-                        }
-                    }
-                }
-                info!("CLIENT: Websocket closed!");
-                Ok::<(), ws::result::Error>(())
-            };
-            let stream_client = async move {
-                while let Ok(message) = channel_recv.recv().await {
-                    connection_send.send(message).await?;
-                }
-                info!("CLIENT: Channel closed (stream disconnected)");
-                Ok::<(), ws::result::Error>(())
-            };
-
-            rocket::tokio::select!(
-                res = client_stream => res,
-                res = stream_client => res,
-            )
+            activity.viewer_joined();
+            metrics.viewer_joined();
+            let res = session.run(connection).await;
+            activity.viewer_left();
+            metrics.viewer_left();
+            res
         })
     }))
 }
@@ -350,10 +849,25 @@ fn rocket() -> _ {
     rocket::build()
         .mount(
             "/",
-            routes![index, new_lobby, connect_streamer, connect_user],
+            routes![
+                index,
+                new_lobby,
+                lobby_status,
+                close_lobby,
+                rotate_key,
+                connect_streamer,
+                connect_user
+            ],
         )
+        .mount("/", routes![metrics::metrics])
+        .mount("/admin", routes![admin::list_lobbies])
         .register("/", catchers![default_catcher])
-        .manage(Lobbies::default())
+        .attach(registry::fairing())
+        .attach(auth::fairing())
+        .attach(reaper::fairing())
+        .attach(admin::fairing())
+        .attach(public_url::fairing())
+        .attach(heartbeat::fairing())
         .attach(cors.to_cors().expect("Failed to create cors"))
 }
 
@@ -361,29 +875,37 @@ fn rocket() -> _ {
 mod tests {
     #![allow(clippy::unwrap_used)]
 
+    use rocket::http::Header;
+    use rocket::local::blocking::{Client, LocalRequest};
+    use rocket::serde::json::serde_json;
+    use rocket::{Build, Rocket};
+
     use super::*;
+
+    /// Rocket instance configured with the test extension secret
+    pub fn test_rocket() -> Rocket<Build> {
+        let figment = rocket::Config::figment().merge(("extension_secret", auth::tests::SECRET));
+        rocket().configure(figment)
+    }
+
+    /// Add the headers needed for rocket to accept the request as a websocket upgrade
+    fn upgrade(request: LocalRequest<'_>) -> LocalRequest<'_> {
+        request
+            .header(Header::new("Connection", "Upgrade"))
+            .header(Header::new("Upgrade", "websocket"))
+            .header(Header::new("Sec-WebSocket-Version", "13"))
+            .header(Header::new("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="))
+    }
+
     mod result_ext {
         use super::*;
 
-        #[test]
-        fn unknown_with_pass() {
-            let res: Result<u8, ()> = Ok(10);
-            assert_eq!(res.unknown_with("test"), Ok(10));
-        }
         #[test]
         fn unknown_pass() {
             let res: Result<u8, ()> = Ok(10);
             assert_eq!(res.unknown(), Ok(10));
         }
         #[test]
-        fn unknown_with_fail() {
-            let res: Result<u8, ()> = Err(());
-            assert_eq!(
-                res.unknown_with("test"),
-                Err(Errors::Unknown("test".into()))
-            );
-        }
-        #[test]
         fn unknown_fail() {
             let x = "hello world";
             let res: Result<u8, _> = Err(x);
@@ -391,27 +913,1193 @@ mod tests {
         }
     }
 
-    mod create_lobby {
-        use rocket::local::blocking::Client;
+    #[test]
+    fn empty_secret_fails_ignition() {
+        let figment = rocket::Config::figment().merge(("extension_secret", ""));
+        let err = Client::tracked(rocket().configure(figment)).err().unwrap();
+        assert!(matches!(
+            err.kind(),
+            rocket::error::ErrorKind::FailedFairings(_)
+        ));
+    }
 
+    mod create_lobby {
         use super::*;
+        use crate::rate_limit::LimitPer;
+
+        #[rocket::async_test]
+        async fn duplicate_not_counted() {
+            let lobbies = Lobbies::default();
+            for _ in 0..2 {
+                let lobby = Lobby::new(
+                    Arc::from("viv"),
+                    LobbyOptions::default(),
+                    lobbies.metrics.clone(),
+                );
+                let _ = lobbies.insert(lobby).await;
+            }
+
+            let text = lobbies.metrics.render().unwrap();
+            assert!(text.contains("minimap_lobbies 1\n"));
+            assert!(text.contains("minimap_lobby_lifetime_seconds_count 0\n"));
+        }
 
         #[test]
         fn create() {
-            let client = Client::tracked(rocket()).unwrap();
-            let response = client.post(uri!(new_lobby("viv"))).dispatch();
+            let client = Client::tracked(test_rocket()).unwrap();
+            let response = client.post("/lobby/new?user=viv").dispatch();
 
             assert_eq!(response.status(), Status::Created);
         }
 
+        #[test]
+        fn login() {
+            let client = Client::tracked(test_rocket()).unwrap();
+            let response = client.post("/lobby/new?user=viv").dispatch();
+
+            let location = response.headers().get_one("Location").unwrap().to_owned();
+            let login = response.into_json::<Value>().unwrap();
+            let key = login["key"].as_str().unwrap();
+            let url = format!("ws://localhost:8000/lobby/connect/streamer?user=viv&key={key}");
+            assert_eq!(login["url"], url);
+            assert_eq!(location, url);
+        }
+
         #[test]
         fn duplicate() {
-            let client = Client::tracked(rocket()).unwrap();
+            let client = Client::tracked(test_rocket()).unwrap();
 
-            client.post(uri!(new_lobby("viv"))).dispatch();
-            let response = client.post(uri!(new_lobby("viv"))).dispatch();
+            client.post("/lobby/new?user=viv").dispatch();
+            let response = client.post("/lobby/new?user=viv").dispatch();
 
             assert_eq!(response.status(), Status::Conflict);
         }
+
+        #[test]
+        fn options() {
+            let client = Client::tracked(test_rocket()).unwrap();
+            let lobbies = client.rocket().state::<Lobbies>().unwrap();
+
+            client.post("/lobby/new?user=viv").dispatch();
+            client
+                .post("/lobby/new?user=alice&lag=disconnect")
+                .dispatch();
+            client
+                .post("/lobby/new?user=eve&limit.burst=5&limit.per=user")
+                .dispatch();
+            let response = client.post("/lobby/new?user=bob&lag=nope").dispatch();
+            let no_refill = client
+                .post("/lobby/new?user=bob&limit.per_second=0")
+                .dispatch();
+
+            let channels = lobbies.channels.read().unwrap();
+            assert_eq!(channels["viv"].options.lag, LagPolicy::Resync);
+            assert_eq!(channels["viv"].options.limit, RateLimit::default());
+            assert_eq!(channels["alice"].options.lag, LagPolicy::Disconnect);
+            assert_eq!(channels["eve"].options.limit.burst, 5);
+            assert_eq!(channels["eve"].options.limit.per, LimitPer::User);
+            assert_eq!(response.status(), Status::UnprocessableEntity);
+            assert_eq!(no_refill.status(), Status::UnprocessableEntity);
+        }
+
+        #[test]
+        fn zero_limits() {
+            let client = Client::tracked(test_rocket()).unwrap();
+
+            for limit in ["burst", "per_second", "max_size"] {
+                let response = client
+                    .post(format!("/lobby/new?user=viv&limit.{limit}=0"))
+                    .dispatch();
+                assert_eq!(response.status(), Status::UnprocessableEntity, "{limit}");
+            }
+            assert!(client
+                .rocket()
+                .state::<Lobbies>()
+                .unwrap()
+                .channels
+                .read()
+                .unwrap()
+                .is_empty());
+        }
+
+        #[test]
+        fn protocol() {
+            let client = Client::tracked(test_rocket()).unwrap();
+            let lobbies = client.rocket().state::<Lobbies>().unwrap();
+
+            client.post("/lobby/new?user=viv").dispatch();
+            client
+                .post("/lobby/new?user=eve&protocol.version=1&protocol.features=css&protocol.features=reset")
+                .dispatch();
+            let unknown = client
+                .post("/lobby/new?user=bob&protocol.version=2")
+                .dispatch();
+            assert_eq!(unknown.status(), Status::UnprocessableEntity);
+
+            let status = client.get(uri!(lobby_status("eve"))).dispatch();
+            let status: Value = status.into_json().unwrap();
+            assert_eq!(status["protocol"]["features"][1], "reset");
+
+            let channels = lobbies.channels.read().unwrap();
+            assert_eq!(channels["viv"].options.protocol, Declaration::default());
+            assert_eq!(channels["eve"].options.protocol.version, 1);
+            assert_eq!(channels["eve"].options.protocol.features, ["css", "reset"]);
+        }
+    }
+
+    mod stamp_viewer_message {
+        use super::*;
+        use crate::auth::Role;
+
+        fn viewer() -> ViewerIdentity {
+            ViewerIdentity {
+                opaque_user_id: "U123".into(),
+                user_id: None,
+                role: Role::Moderator,
+                connection_id: "abc".into(),
+            }
+        }
+
+        fn stamp(message: &ws::Message) -> Option<Value> {
+            stamp_viewer_message(&viewer(), message)
+                .map(|message| serde_json::from_str(message.to_text().unwrap()).unwrap())
+        }
+
+        #[test]
+        fn json() {
+            let stamped = stamp(&ws::Message::Text(r#"{"x": 0.5, "userId": "fake"}"#.into()));
+
+            assert_eq!(
+                stamped,
+                Some(serde_json::json!({
+                    "viewer": {
+                        "opaqueUserId": "U123",
+                        "userId": null,
+                        "role": "moderator",
+                        "connectionId": "abc",
+                    },
+                    "data": {"x": 0.5, "userId": "fake"},
+                }))
+            );
+        }
+
+        #[test]
+        fn plain_text() {
+            let stamped = stamp(&ws::Message::Text("Hello Server!".into())).unwrap();
+            assert_eq!(stamped["data"], "Hello Server!");
+        }
+
+        #[test]
+        fn binary() {
+            assert_eq!(stamp(&ws::Message::Binary(vec![1, 2, 3])), None);
+        }
+
+        #[test]
+        fn msgpack() {
+            let data = serde_json::json!({"x": 0.5});
+            let message = ws::Message::Binary(rmp_serde::to_vec_named(&data).unwrap());
+            let stamped = stamp_viewer_message(&viewer(), &message).unwrap();
+
+            assert!(stamped.is_binary());
+            let stamped = encoding::decode(&stamped).unwrap();
+            assert_eq!(stamped["data"], data);
+            assert_eq!(stamped["viewer"]["connectionId"], "abc");
+        }
+    }
+
+    mod lobby_status {
+        use super::*;
+
+        #[test]
+        fn missing() {
+            let client = Client::tracked(test_rocket()).unwrap();
+            let response = client.get(uri!(lobby_status("viv"))).dispatch();
+
+            assert_eq!(response.status(), Status::NotFound);
+        }
+
+        #[test]
+        fn waiting() {
+            let client = Client::tracked(test_rocket()).unwrap();
+            client.post("/lobby/new?user=viv").dispatch();
+            let response = client.get(uri!(lobby_status("viv"))).dispatch();
+
+            assert_eq!(response.status(), Status::Ok);
+            let status = response.into_json::<Value>().unwrap();
+            assert_eq!(status["game"], "waiting");
+            assert_eq!(status["viewers"], 0);
+            assert!(status["createdAt"].as_u64().unwrap() > 0);
+            assert!(status.get("streamerKey").is_none());
+        }
+    }
+
+    mod manage_lobby {
+        use super::*;
+        use crate::auth::tests::token;
+
+        /// Create a lobby for viv and return its key
+        fn create(client: &Client) -> String {
+            client
+                .post("/lobby/new?user=viv")
+                .dispatch()
+                .into_json::<Value>()
+                .unwrap()["key"]
+                .as_str()
+                .unwrap()
+                .to_owned()
+        }
+
+        #[test]
+        fn close_with_key() {
+            let client = Client::tracked(test_rocket()).unwrap();
+            let key = create(&client);
+
+            let response = client
+                .delete(uri!(close_lobby("viv", Some(key), None::<&str>)))
+                .dispatch();
+            assert_eq!(response.status(), Status::NoContent);
+
+            let response = client.post("/lobby/new?user=viv").dispatch();
+            assert_eq!(response.status(), Status::Created);
+        }
+
+        #[test]
+        fn close_with_broadcaster_token() {
+            let client = Client::tracked(test_rocket()).unwrap();
+            create(&client);
+
+            let token = token("viv", Role::Broadcaster, 3600);
+            let response = client
+                .delete(uri!(close_lobby("viv", None::<&str>, Some(token))))
+                .dispatch();
+            assert_eq!(response.status(), Status::NoContent);
+        }
+
+        #[test]
+        fn close_with_viewer_token() {
+            let client = Client::tracked(test_rocket()).unwrap();
+            create(&client);
+
+            let token = token("viv", Role::Viewer, 3600);
+            let response = client
+                .delete(uri!(close_lobby("viv", None::<&str>, Some(token))))
+                .dispatch();
+            assert_eq!(response.status(), Status::Forbidden);
+        }
+
+        #[test]
+        fn close_without_credentials() {
+            let client = Client::tracked(test_rocket()).unwrap();
+            create(&client);
+
+            let response = client
+                .delete(uri!(close_lobby("viv", None::<&str>, None::<&str>)))
+                .dispatch();
+            assert_eq!(response.status(), Status::Unauthorized);
+        }
+
+        #[test]
+        fn rotate() {
+            let client = Client::tracked(test_rocket()).unwrap();
+            let old_key = create(&client);
+
+            let response = client
+                .post(uri!(rotate_key("viv", Some(&old_key), None::<&str>)))
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let new_key = response.into_json::<Value>().unwrap()["key"]
+                .as_str()
+                .unwrap()
+                .to_owned();
+            assert_ne!(old_key, new_key);
+
+            let response =
+                upgrade(client.get(uri!(connect_streamer("viv", &old_key, _, _)))).dispatch();
+            assert_eq!(response.status(), Status::Forbidden);
+            // The local client does not perform the upgrade, so this is not a 101
+            let response =
+                upgrade(client.get(uri!(connect_streamer("viv", &new_key, _, _)))).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+        }
+
+        #[test]
+        fn rotate_with_wrong_key() {
+            let client = Client::tracked(test_rocket()).unwrap();
+            create(&client);
+
+            let response = client
+                .post(uri!(rotate_key("viv", Some("nope"), None::<&str>)))
+                .dispatch();
+            assert_eq!(response.status(), Status::Forbidden);
+        }
+    }
+
+    mod restart {
+        use std::path::PathBuf;
+
+        use super::*;
+
+        /// Directory for a lobby store, removed on drop
+        struct Store(PathBuf);
+
+        impl Store {
+            fn new() -> Self {
+                Self(std::env::temp_dir().join(format!("lobbies-{}", uuid::Uuid::new_v4())))
+            }
+
+            /// Start a server using the store
+            fn client(&self) -> Client {
+                let figment = rocket::Config::figment()
+                    .merge(("extension_secret", auth::tests::SECRET))
+                    .merge(("lobby_store", &self.0));
+                Client::tracked(rocket().configure(figment)).unwrap()
+            }
+        }
+
+        impl Drop for Store {
+            fn drop(&mut self) {
+                let _ = std::fs::remove_dir_all(&self.0);
+            }
+        }
+
+        /// Create a lobby for viv and return its key
+        fn create(client: &Client) -> String {
+            client
+                .post("/lobby/new?user=viv&lag=coalesce")
+                .dispatch()
+                .into_json::<Value>()
+                .unwrap()["key"]
+                .as_str()
+                .unwrap()
+                .to_owned()
+        }
+
+        #[test]
+        fn resume_after_restart() {
+            let store = Store::new();
+            let key = create(&store.client());
+
+            let client = store.client();
+            let lobbies = client.rocket().state::<Lobbies>().unwrap();
+            {
+                let channels = lobbies.channels.read().unwrap();
+                assert_eq!(channels["viv"].streamer_key, key);
+                assert_eq!(channels["viv"].options.lag, LagPolicy::Coalesce);
+            }
+            let status = client.get(uri!(lobby_status("viv"))).dispatch();
+            assert_eq!(status.into_json::<Value>().unwrap()["game"], "disconnected");
+
+            // The local client does not upgrade, but the key is accepted
+            let response =
+                upgrade(client.get(uri!(connect_streamer("viv", key.as_str(), _, _)))).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let response =
+                upgrade(client.get(uri!(connect_streamer("viv", "wrong", _, _)))).dispatch();
+            assert_ne!(response.status(), Status::Ok);
+        }
+
+        #[test]
+        fn rotated_key_is_stored() {
+            let store = Store::new();
+            let client = store.client();
+            let key = create(&client);
+            let rotated = client
+                .post(uri!(rotate_key("viv", Some(key), None::<&str>)))
+                .dispatch()
+                .into_json::<Value>()
+                .unwrap()["key"]
+                .clone();
+            drop(client);
+
+            let client = store.client();
+            let lobbies = client.rocket().state::<Lobbies>().unwrap();
+            assert_eq!(
+                lobbies.channels.read().unwrap()["viv"].streamer_key,
+                rotated.as_str().unwrap()
+            );
+        }
+
+        #[test]
+        fn closed_lobby_is_forgotten() {
+            let store = Store::new();
+            let client = store.client();
+            let key = create(&client);
+            client
+                .delete(uri!(close_lobby("viv", Some(key), None::<&str>)))
+                .dispatch();
+            drop(client);
+
+            let client = store.client();
+            let status = client.get(uri!(lobby_status("viv"))).dispatch();
+            assert_eq!(status.status(), Status::NotFound);
+        }
+    }
+
+    mod connect_user {
+        use super::*;
+        use crate::auth::tests::token;
+        use crate::auth::Role;
+
+        #[test]
+        fn missing_token() {
+            let client = Client::tracked(test_rocket()).unwrap();
+            let response =
+                upgrade(client.get(uri!(connect_user("viv", None::<&str>, _, _)))).dispatch();
+
+            assert_eq!(response.status(), Status::Unauthorized);
+        }
+
+        #[test]
+        fn wrong_channel() {
+            let client = Client::tracked(test_rocket()).unwrap();
+            let token = token("other", Role::Viewer, 3600);
+            let response =
+                upgrade(client.get(uri!(connect_user("viv", Some(token), _, _)))).dispatch();
+
+            assert_eq!(response.status(), Status::Forbidden);
+        }
+
+        #[test]
+        fn expired_token() {
+            let client = Client::tracked(test_rocket()).unwrap();
+            let token = token("viv", Role::Viewer, -3600);
+            let response =
+                upgrade(client.get(uri!(connect_user("viv", Some(token), _, _)))).dispatch();
+
+            assert_eq!(response.status(), Status::Unauthorized);
+        }
+
+        #[test]
+        fn valid_token_no_lobby() {
+            let client = Client::tracked(test_rocket()).unwrap();
+            let token = token("viv", Role::Viewer, 3600);
+            let response =
+                upgrade(client.get(uri!(connect_user("viv", Some(token), _, _)))).dispatch();
+
+            assert_eq!(response.status(), Status::NotFound);
+        }
+    }
+
+    /// A server listening on a random port, for tests that need real websockets
+    mod live {
+        use std::collections::BTreeSet;
+        use std::time::Duration;
+
+        use rocket::fairing::AdHoc;
+        use rocket::figment::Figment;
+        use rocket::futures::{SinkExt, StreamExt};
+        use rocket::tokio::net::TcpStream;
+        use rocket::tokio::time::timeout;
+        use rocket::Shutdown;
+        use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+        use twitch_minimap_protocol::CloseReason;
+
+        use super::*;
+        use crate::auth::tests::token;
+        use crate::auth::Role;
+
+        /// Client side of a websocket connection
+        pub type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+        /// A running server, shut down on drop
+        pub struct Server {
+            /// Port the server listens on
+            pub port: u16,
+            /// The lobbies managed by the server
+            pub lobbies: Lobbies,
+            /// Handle to stop the server
+            pub shutdown: Shutdown,
+        }
+
+        impl Server {
+            /// Launch the server in the background
+            pub async fn launch() -> Self {
+                Self::launch_with(|figment| figment).await
+            }
+
+            /// Launch the server in the background, with extra configuration
+            pub async fn launch_with(configure: impl FnOnce(Figment) -> Figment) -> Self {
+                let (port_send, port_recv) = sync::oneshot::channel();
+                let figment = configure(
+                    rocket::Config::figment()
+                        .merge(("extension_secret", auth::tests::SECRET))
+                        .merge(("port", 0))
+                        .merge(("log_level", "off")),
+                );
+                let rocket = rocket()
+                    .configure(figment)
+                    .attach(AdHoc::on_liftoff("Report port", |rocket| {
+                        Box::pin(async move {
+                            let _ = port_send.send(rocket.config().port);
+                        })
+                    }))
+                    .ignite()
+                    .await
+                    .unwrap();
+
+                let lobbies = rocket.state::<Lobbies>().unwrap().clone();
+                let shutdown = rocket.shutdown();
+                rocket::tokio::spawn(rocket.launch());
+
+                Self {
+                    port: port_recv.await.unwrap(),
+                    lobbies,
+                    shutdown,
+                }
+            }
+
+            /// Create a lobby for `user` and return the streamer key
+            pub fn lobby(&self, user: &str) -> String {
+                self.lobby_with(user, LobbyOptions::default())
+            }
+
+            /// Create a lobby for `user` with `options` and return the streamer key
+            pub fn lobby_with(&self, user: &str, options: LobbyOptions) -> String {
+                let lobby = Lobby::new(Arc::from(user), options, self.lobbies.metrics.clone());
+                let key = lobby.streamer_key.clone();
+                self.lobbies
+                    .channels
+                    .write()
+                    .unwrap()
+                    .insert(Arc::from(user), lobby);
+                key
+            }
+
+            /// State of the streamer of `user`s lobby
+            pub fn streamer_state(&self, user: &str) -> StreamerState {
+                *self.lobbies.channels.read().unwrap()[user]
+                    .streamer
+                    .borrow()
+            }
+
+            /// Open a websocket to `path`
+            pub async fn connect(&self, path: &str) -> Socket {
+                let url = format!("ws://127.0.0.1:{}{path}", self.port);
+                tokio_tungstenite::connect_async(url).await.unwrap().0
+            }
+
+            /// Connect as the streamer of `user`
+            pub async fn streamer(&self, user: &str, key: &str) -> Socket {
+                let socket = self
+                    .connect(&format!("/lobby/connect/streamer?user={user}&key={key}"))
+                    .await;
+                // The lobby only counts the game as connected once the upgrade went through
+                wait_until(|| matches!(self.streamer_state(user), StreamerState::Connected(_)))
+                    .await;
+                socket
+            }
+
+            /// Connect as a viewer of `user`, past the protocol the game speaks
+            pub async fn viewer(&self, user: &str) -> Socket {
+                let token = token(user, Role::Viewer, 3600);
+                let mut socket = self
+                    .connect(&format!("/lobby/connect?user={user}&token={token}"))
+                    .await;
+                assert!(recv_text(&mut socket).await.starts_with(r#"{"protocol":"#));
+                socket
+            }
+        }
+
+        impl Drop for Server {
+            fn drop(&mut self) {
+                self.shutdown.clone().notify();
+            }
+        }
+
+        /// Wait for the next message on `socket`
+        pub async fn recv(socket: &mut Socket) -> Option<ws::Message> {
+            timeout(Duration::from_secs(5), socket.next())
+                .await
+                .unwrap()
+                .and_then(Result::ok)
+        }
+
+        /// Wait for the next text message on `socket`
+        pub async fn recv_text(socket: &mut Socket) -> String {
+            recv(socket).await.unwrap().into_text().unwrap()
+        }
+
+        /// Wait for the next message on `socket` and check it is msgpack
+        pub async fn recv_msgpack(socket: &mut Socket) -> Value {
+            let message = recv(socket).await.unwrap();
+            assert!(message.is_binary());
+            encoding::decode(&message).unwrap()
+        }
+
+        /// Wait for the next text message on `socket` that is not a presence event
+        pub async fn recv_forwarded(socket: &mut Socket) -> String {
+            loop {
+                let text = recv_text(socket).await;
+                if !text.starts_with(r#"{"event":"#) {
+                    return text;
+                }
+            }
+        }
+
+        /// Wait until `f` returns true
+        pub async fn wait_until(mut f: impl FnMut() -> bool) {
+            timeout(Duration::from_secs(5), async {
+                while !f() {
+                    rocket::tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+        }
+
+        #[rocket::async_test]
+        async fn viewers_survive_resume() {
+            let server = Server::launch().await;
+            let key = server.lobby("viv");
+            let mut streamer = server.streamer("viv", &key).await;
+            let mut viewer = server.viewer("viv").await;
+
+            streamer
+                .send(ws::Message::Text("one".into()))
+                .await
+                .unwrap();
+            assert_eq!(recv_text(&mut viewer).await, "one");
+
+            streamer.close(None).await.unwrap();
+            wait_until(|| matches!(server.streamer_state("viv"), StreamerState::Disconnected(_)))
+                .await;
+
+            let mut streamer = server.streamer("viv", &key).await;
+            streamer
+                .send(ws::Message::Text("two".into()))
+                .await
+                .unwrap();
+            assert_eq!(recv_text(&mut viewer).await, "two");
+
+            viewer.send(ws::Message::Text("{}".into())).await.unwrap();
+            assert!(recv_forwarded(&mut streamer).await.contains("viewer"));
+        }
+
+        #[rocket::async_test]
+        async fn close_sends_reason() {
+            let server = Server::launch().await;
+            let key = server.lobby("viv");
+            let mut streamer = server.streamer("viv", &key).await;
+            let mut viewer = server.viewer("viv").await;
+
+            server.lobbies.channels.read().unwrap()["viv"].close("Bye");
+
+            for socket in [&mut streamer, &mut viewer] {
+                // The streamer may still get the viewer joining first
+                let message = loop {
+                    match recv(socket).await {
+                        Some(ws::Message::Text(_)) => {}
+                        message => break message,
+                    }
+                };
+                assert!(matches!(
+                    message,
+                    Some(ws::Message::Close(Some(frame)))
+                        if frame.reason == "Bye" && u16::from(frame.code) == 1001
+                ));
+            }
+        }
+
+        #[rocket::async_test]
+        async fn close_with_full_inbox() {
+            let server = Server::launch().await;
+            let key = server.lobby("viv");
+            let mut streamer = server.streamer("viv", &key).await;
+            streamer.close(None).await.unwrap();
+            wait_until(|| matches!(server.streamer_state("viv"), StreamerState::Disconnected(_)))
+                .await;
+
+            // Nobody drains the inbox while the game is away
+            let inbox = server.lobbies.channels.read().unwrap()["viv"]
+                .channels
+                .client_to_streamer
+                .clone();
+            while inbox.try_send(ws::Message::Text("{}".into())).is_ok() {}
+
+            let _viewer = server.viewer("viv").await;
+            let metrics = server.lobbies.metrics.clone();
+            wait_until(|| metrics.render().unwrap().contains("minimap_viewers 1\n")).await;
+
+            server.lobbies.channels.read().unwrap()["viv"].close("Bye");
+            wait_until(|| metrics.render().unwrap().contains("minimap_viewers 0\n")).await;
+        }
+
+        #[rocket::async_test]
+        async fn replace_connection() {
+            let server = Server::launch().await;
+            let key = server.lobby("viv");
+            let mut old = server.streamer("viv", &key).await;
+            let mut new = server.streamer("viv", &key).await;
+            let mut viewer = server.viewer("viv").await;
+
+            assert!(matches!(
+                recv(&mut old).await,
+                Some(ws::Message::Close(Some(frame)))
+                    if CloseReason::from_code(frame.code.into()) == Some(CloseReason::TakenOver)
+            ));
+
+            new.send(ws::Message::Text("new".into())).await.unwrap();
+            assert_eq!(recv_text(&mut viewer).await, "new");
+            viewer.send(ws::Message::Text("{}".into())).await.unwrap();
+            assert!(recv_forwarded(&mut new).await.contains("viewer"));
+        }
+
+        #[rocket::async_test]
+        async fn rotating_key_disconnects_game() {
+            let server = Server::launch().await;
+            let key = server.lobby("viv");
+            let mut streamer = server.streamer("viv", &key).await;
+
+            // What rotating the key does to the connected game
+            server.lobbies.channels.read().unwrap()["viv"]
+                .streamer
+                .send_replace(StreamerState::Disconnected(Instant::now()));
+
+            assert!(matches!(
+                recv(&mut streamer).await,
+                Some(ws::Message::Close(Some(frame)))
+                    if CloseReason::from_code(frame.code.into()) == Some(CloseReason::KeyRotated)
+            ));
+        }
+
+        #[rocket::async_test]
+        async fn late_viewer_gets_snapshot() {
+            let server = Server::launch().await;
+            let key = server.lobby("viv");
+            let mut streamer = server.streamer("viv", &key).await;
+
+            for message in [
+                r#"{"data":{"css":"a"}}"#,
+                r#"{"data":[]}"#,
+                r#"{"data":{"css":"b"}}"#,
+            ] {
+                streamer
+                    .send(ws::Message::Text(message.into()))
+                    .await
+                    .unwrap();
+            }
+            wait_until(|| {
+                let channels = server.lobbies.channels.read().unwrap();
+                channels["viv"]
+                    .channels
+                    .streamer_to_client
+                    .subscribe()
+                    .1
+                    .len()
+                    == 2
+            })
+            .await;
+
+            let mut viewer = server.viewer("viv").await;
+            streamer
+                .send(ws::Message::Text("live".into()))
+                .await
+                .unwrap();
+            assert_eq!(recv_text(&mut viewer).await, r#"{"data":[]}"#);
+            assert_eq!(recv_text(&mut viewer).await, r#"{"data":{"css":"b"}}"#);
+            assert_eq!(recv_text(&mut viewer).await, "live");
+        }
+
+        #[rocket::async_test]
+        async fn pings_are_not_forwarded() {
+            let server = Server::launch().await;
+            let key = server.lobby("viv");
+            let mut streamer = server.streamer("viv", &key).await;
+            let mut viewer = server.viewer("viv").await;
+
+            streamer.send(ws::Message::Ping(vec![1])).await.unwrap();
+            streamer
+                .send(ws::Message::Text("after".into()))
+                .await
+                .unwrap();
+            assert_eq!(recv_text(&mut viewer).await, "after");
+        }
+
+        #[rocket::async_test]
+        async fn dead_streamer_times_out() {
+            let server = Server::launch_with(|figment| {
+                figment
+                    .merge(("ping_interval", 1))
+                    .merge(("ping_timeout", 3))
+            })
+            .await;
+            let key = server.lobby("viv");
+            let _streamer = server.streamer("viv", &key).await;
+            let mut viewer = server.viewer("viv").await;
+            // Reading answers pings, so the viewer stays connected
+            let pinged = rocket::tokio::spawn(async move {
+                let mut pinged = false;
+                while let Some(Ok(message)) = viewer.next().await {
+                    pinged |= message.is_ping();
+                }
+                pinged
+            });
+
+            // The streamer never reads, so it never answers a ping
+            wait_until(|| matches!(server.streamer_state("viv"), StreamerState::Disconnected(_)))
+                .await;
+            let activity = server.lobbies.channels.read().unwrap()["viv"]
+                .activity
+                .get()
+                .unwrap();
+            assert_eq!(activity.viewers, 1);
+
+            server.lobbies.channels.read().unwrap()["viv"].close("Bye");
+            assert!(pinged.await.unwrap());
+        }
+
+        #[rocket::async_test]
+        async fn addressed_to_one_viewer() {
+            let server = Server::launch().await;
+            let key = server.lobby("viv");
+            let mut streamer = server.streamer("viv", &key).await;
+            let mut target = server.viewer("viv").await;
+            let mut other = server.viewer("viv").await;
+
+            target.send(ws::Message::Text("{}".into())).await.unwrap();
+            let stamped: Value =
+                serde_json::from_str(&recv_forwarded(&mut streamer).await).unwrap();
+            let connection_id = stamped["viewer"]["connectionId"].as_str().unwrap();
+
+            let addressed = format!(r#"{{"to":"{connection_id}","data":"secret"}}"#);
+            streamer.send(ws::Message::Text(addressed)).await.unwrap();
+            streamer
+                .send(ws::Message::Text("everyone".into()))
+                .await
+                .unwrap();
+            streamer
+                .send(ws::Message::Text("done".into()))
+                .await
+                .unwrap();
+
+            // Addressed messages do not queue behind the others, so only what arrives is fixed
+            let mut received = BTreeSet::new();
+            for _ in 0..3 {
+                received.insert(recv_text(&mut target).await);
+            }
+            assert_eq!(
+                received,
+                BTreeSet::from([r#"{"data":"secret"}"#, "everyone", "done"].map(String::from))
+            );
+            let mut received = BTreeSet::new();
+            for _ in 0..2 {
+                received.insert(recv_text(&mut other).await);
+            }
+            assert_eq!(
+                received,
+                BTreeSet::from(["everyone", "done"].map(String::from))
+            );
+        }
+
+        #[rocket::async_test]
+        async fn presence() {
+            let server = Server::launch().await;
+            let key = server.lobby("viv");
+            let mut streamer = server.streamer("viv", &key).await;
+            let viewer = server.viewer("viv").await;
+
+            let joined: Value = serde_json::from_str(&recv_text(&mut streamer).await).unwrap();
+            assert_eq!(joined["event"], "viewer_joined");
+            assert_eq!(joined["viewer"]["userId"], "123");
+            let connection_id = &joined["viewer"]["connectionId"];
+            assert!(connection_id.is_string());
+
+            drop(viewer);
+            let left: Value = serde_json::from_str(&recv_text(&mut streamer).await).unwrap();
+            assert_eq!(left["event"], "viewer_left");
+            assert_eq!(left["viewer"]["connectionId"], *connection_id);
+        }
+
+        #[rocket::async_test]
+        async fn msgpack() {
+            let server = Server::launch().await;
+            let key = server.lobby("viv");
+            let mut streamer = server
+                .connect(&format!(
+                    "/lobby/connect/streamer?user=viv&key={key}&encoding=msgpack"
+                ))
+                .await;
+            let mut json_viewer = server.viewer("viv").await;
+            let token = token("viv", Role::Viewer, 3600);
+            let mut msgpack_viewer = server
+                .connect(&format!(
+                    "/lobby/connect?user=viv&token={token}&encoding=msgpack"
+                ))
+                .await;
+            assert_eq!(
+                recv_msgpack(&mut msgpack_viewer).await["protocol"]["version"],
+                1
+            );
+
+            // The game gets everything as msgpack, even what json viewers and the server send
+            for _ in 0..2 {
+                assert_eq!(recv_msgpack(&mut streamer).await["event"], "viewer_joined");
+            }
+            json_viewer
+                .send(ws::Message::Text(r#"{"x": 0.5}"#.into()))
+                .await
+                .unwrap();
+            assert_eq!(recv_msgpack(&mut streamer).await["data"]["x"], 0.5);
+
+            let units = serde_json::json!({"data": [{"id": 1, "kind": "Sphere", "x": 0.5}]});
+            let frame = ws::Message::Binary(rmp_serde::to_vec_named(&units).unwrap());
+            streamer.send(frame.clone()).await.unwrap();
+            assert_eq!(recv(&mut msgpack_viewer).await, Some(frame));
+            assert_eq!(recv_text(&mut json_viewer).await, units.to_string());
+
+            let raw = ws::Message::Binary(vec![1, 2, 3]);
+            streamer.send(raw.clone()).await.unwrap();
+            assert_eq!(recv(&mut msgpack_viewer).await, Some(raw.clone()));
+            assert_eq!(recv(&mut json_viewer).await, Some(raw));
+        }
+
+        #[rocket::async_test]
+        async fn protocol() {
+            let server = Server::launch().await;
+            let options = LobbyOptions {
+                protocol: Declaration {
+                    version: 1,
+                    features: vec!["css".into(), "reset".into()],
+                },
+                ..LobbyOptions::default()
+            };
+            let key = server.lobby_with("viv", options);
+            let _streamer = server.streamer("viv", &key).await;
+            let token = token("viv", Role::Viewer, 3600);
+
+            let mut viewer = server
+                .connect(&format!("/lobby/connect?user=viv&token={token}&protocol=1"))
+                .await;
+            assert_eq!(
+                recv_text(&mut viewer).await,
+                r#"{"protocol":{"version":1,"features":["css","reset"]}}"#
+            );
+
+            let mut outdated = server
+                .connect(&format!("/lobby/connect?user=viv&token={token}&protocol=2"))
+                .await;
+            let refused = ws::frame::CloseFrame {
+                code: ws::frame::CloseCode::Protocol,
+                reason: "The game speaks protocol version 1, this client speaks version 2".into(),
+            };
+            assert_eq!(
+                recv(&mut outdated).await,
+                Some(ws::Message::Close(Some(refused)))
+            );
+
+            let mut game = server
+                .connect(&format!(
+                    "/lobby/connect/streamer?user=viv&key={key}&protocol=2"
+                ))
+                .await;
+            assert!(matches!(recv(&mut game).await, Some(ws::Message::Close(_))));
+        }
+
+        #[rocket::async_test]
+        async fn shared_between_instances() {
+            let url = crate::backend::tests::stand_in().await;
+            let first = Server::launch_with(|figment| figment.merge(("lobby_backend", &url))).await;
+            let second =
+                Server::launch_with(|figment| figment.merge(("lobby_backend", &url))).await;
+            let key = first
+                .lobbies
+                .create("viv", LobbyOptions::default())
+                .await
+                .unwrap();
+            let mut streamer = first.streamer("viv", &key).await;
+            let mut viewer = second.viewer("viv").await;
+
+            let joined: Value = serde_json::from_str(&recv_text(&mut streamer).await).unwrap();
+            assert_eq!(joined["event"], "viewer_joined");
+
+            viewer
+                .send(ws::Message::Text("click".into()))
+                .await
+                .unwrap();
+            let forwarded: Value =
+                serde_json::from_str(&recv_forwarded(&mut streamer).await).unwrap();
+            assert_eq!(forwarded["data"], "click");
+
+            streamer
+                .send(ws::Message::Text("hello".into()))
+                .await
+                .unwrap();
+            assert_eq!(recv_text(&mut viewer).await, "hello");
+
+            first.lobbies.close("viv", "Bye").await;
+            assert!(matches!(
+                recv(&mut viewer).await,
+                Some(ws::Message::Close(Some(frame))) if frame.reason == "Bye"
+            ));
+            wait_until(|| second.lobbies.channels.read().unwrap().is_empty()).await;
+        }
+
+        #[rocket::async_test]
+        async fn copies_replay_sticky_and_outlive_quiet_game() {
+            let url = crate::backend::tests::stand_in().await;
+            let launch = || {
+                Server::launch_with(|figment| {
+                    figment
+                        .merge(("lobby_backend", &url))
+                        .merge(("reaper_interval", 1))
+                        .merge(("streamer_grace_period", 1))
+                })
+            };
+            let first = launch().await;
+            let second = launch().await;
+            let key = first
+                .lobbies
+                .create("viv", LobbyOptions::default())
+                .await
+                .unwrap();
+            let mut streamer = first.streamer("viv", &key).await;
+            let css = r#"{"data":{"css":"._1 {}"}}"#;
+            streamer.send(ws::Message::Text(css.into())).await.unwrap();
+            wait_until(|| {
+                let (_, snapshot) = first.lobbies.channels.read().unwrap()["viv"]
+                    .channels
+                    .streamer_to_client
+                    .subscribe();
+                !snapshot.is_empty()
+            })
+            .await;
+
+            let mut viewer = second.viewer("viv").await;
+            assert_eq!(recv_text(&mut viewer).await, css);
+
+            // The game is quiet, but still there
+            rocket::tokio::time::sleep(Duration::from_secs(3)).await;
+            assert!(second.lobbies.channels.read().unwrap().contains_key("viv"));
+            streamer
+                .send(ws::Message::Text("hello".into()))
+                .await
+                .unwrap();
+            assert_eq!(recv_text(&mut viewer).await, "hello");
+        }
+
+        #[rocket::async_test]
+        async fn lobby_of_vanished_instance_expires() {
+            let url = crate::backend::tests::stand_in().await;
+            let shared = || async {
+                let backend = crate::backend::RedisBackend::connect(&url, 1)
+                    .await
+                    .unwrap();
+                Lobbies {
+                    backend: Arc::new(backend),
+                    ..Lobbies::default()
+                }
+            };
+            let gone = shared().await;
+            let here = shared().await;
+
+            // The instance stops without withdrawing or refreshing its lobby
+            gone.create("viv", LobbyOptions::default()).await.unwrap();
+            drop(gone);
+            assert!(matches!(
+                here.create("viv", LobbyOptions::default()).await,
+                Err(Errors::LobbyAlreadyExsists(_))
+            ));
+
+            rocket::tokio::time::sleep(Duration::from_millis(1100)).await;
+            assert!(here.create("viv", LobbyOptions::default()).await.is_ok());
+        }
+
+        #[rocket::async_test]
+        async fn remote_viewers_keep_lobby() {
+            let url = crate::backend::tests::stand_in().await;
+            let launch = || {
+                Server::launch_with(|figment| {
+                    figment
+                        .merge(("lobby_backend", &url))
+                        .merge(("reaper_interval", 1))
+                        .merge(("empty_lobby_ttl", 2))
+                })
+            };
+            let first = launch().await;
+            let second = launch().await;
+            let key = first
+                .lobbies
+                .create("viv", LobbyOptions::default())
+                .await
+                .unwrap();
+            let mut streamer = first.streamer("viv", &key).await;
+            let mut viewer = second.viewer("viv").await;
+            let joined: Value = serde_json::from_str(&recv_text(&mut streamer).await).unwrap();
+            assert_eq!(joined["event"], "viewer_joined");
+
+            // The only viewer is on the second instance, the first must not think it empty
+            rocket::tokio::time::sleep(Duration::from_secs(3)).await;
+            assert!(first.lobbies.channels.read().unwrap().contains_key("viv"));
+
+            viewer.close(None).await.unwrap();
+            wait_until(|| !first.lobbies.channels.read().unwrap().contains_key("viv")).await;
+        }
+
+        #[rocket::async_test]
+        async fn throttled_viewer_is_told() {
+            let server = Server::launch().await;
+            let options = LobbyOptions {
+                limit: RateLimit {
+                    burst: 1,
+                    per_second: 0.5,
+                    ..RateLimit::default()
+                },
+                ..LobbyOptions::default()
+            };
+            let key = server.lobby_with("viv", options);
+            let mut streamer = server.streamer("viv", &key).await;
+            let mut viewer = server.viewer("viv").await;
+
+            for click in ["1", "2", "3"] {
+                viewer.send(ws::Message::Text(click.into())).await.unwrap();
+            }
+            let notice: Value = serde_json::from_str(&recv_text(&mut viewer).await).unwrap();
+            assert_eq!(notice["error"], "rate_limited");
+            assert!(notice["retry_after_ms"].as_u64().unwrap() > 1000);
+
+            // Only the first message got through, and the viewer is told only once
+            assert!(recv_forwarded(&mut streamer).await.contains(r#""data":1"#));
+            streamer
+                .send(ws::Message::Text("after".into()))
+                .await
+                .unwrap();
+            assert_eq!(recv_text(&mut viewer).await, "after");
+        }
+
+        #[rocket::async_test]
+        async fn metrics_count_traffic() {
+            let server = Server::launch().await;
+            let key = server.lobby("viv");
+            let mut streamer = server.streamer("viv", &key).await;
+            let mut viewer = server.viewer("viv").await;
+
+            streamer
+                .send(ws::Message::Text("one".into()))
+                .await
+                .unwrap();
+            assert_eq!(recv_text(&mut viewer).await, "one");
+            viewer
+                .send(ws::Message::Text("x".repeat(1001)))
+                .await
+                .unwrap();
+
+            let metrics = server.lobbies.metrics.clone();
+            wait_until(|| {
+                metrics
+                    .render()
+                    .unwrap()
+                    .contains("minimap_messages_dropped_total{reason=\"too_large\"} 1\n")
+            })
+            .await;
+            let rejected: Value = serde_json::from_str(&recv_text(&mut viewer).await).unwrap();
+            assert_eq!(rejected["error"], "message_too_large");
+            let text = metrics.render().unwrap();
+            assert!(text.contains("minimap_streamers 1\n"));
+            assert!(text.contains("minimap_viewers 1\n"));
+            assert!(text.contains("minimap_forwarded_bytes_total{direction=\"to_viewer\"} 3\n"));
+
+            viewer.close(None).await.unwrap();
+            wait_until(|| metrics.render().unwrap().contains("minimap_viewers 0\n")).await;
+        }
     }
 }
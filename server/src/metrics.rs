@@ -0,0 +1,269 @@
+//! Prometheus metrics, served in the `OpenMetrics` text format at `/metrics`
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use prometheus_client::encoding::text::encode;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::Histogram;
+use prometheus_client::registry::{Registry, Unit};
+use rocket::http::ContentType;
+use rocket::State;
+
+use crate::{Errors, Lobbies, ResultExt};
+
+/// Direction a message is forwarded in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From a viewer to the game
+    ToGame,
+    /// From the game to a viewer
+    ToViewer,
+}
+
+/// Why a message of a viewer was dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// The viewer sent messages too quickly
+    RateLimited,
+    /// The message was over the size limit
+    TooLarge,
+}
+
+/// Label set with a single label
+type Label = [(&'static str, &'static str); 1];
+
+/// Handles to all metrics, cloning shares the underlying values
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    /// Registry all metrics are registered in
+    registry: Arc<Registry>,
+    /// Open lobbies
+    lobbies: Gauge,
+    /// Connected streamers
+    streamers: Gauge,
+    /// Connected viewers across all lobbies
+    viewers: Gauge,
+    /// Forwarded messages, by direction
+    messages: Family<Label, Counter>,
+    /// Forwarded bytes, by direction
+    bytes: Family<Label, Counter>,
+    /// Dropped viewer messages, by reason
+    dropped: Family<Label, Counter>,
+    /// Times a viewer fell behind the broadcast
+    lagged: Counter,
+    /// How long lobbies lived
+    lobby_lifetime: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let metrics = Self {
+            registry: Arc::default(),
+            lobbies: Gauge::default(),
+            streamers: Gauge::default(),
+            viewers: Gauge::default(),
+            messages: Family::default(),
+            bytes: Family::default(),
+            dropped: Family::default(),
+            lagged: Counter::default(),
+            // One minute up to a bit over a day
+            lobby_lifetime: Histogram::new(
+                [
+                    60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0, 28800.0, 86400.0,
+                ]
+                .into_iter(),
+            ),
+        };
+
+        let mut registry = Registry::with_prefix("minimap");
+        registry.register("lobbies", "Open lobbies", metrics.lobbies.clone());
+        registry.register(
+            "streamers",
+            "Streamers connected to their lobby",
+            metrics.streamers.clone(),
+        );
+        registry.register(
+            "viewers",
+            "Viewers connected to a lobby",
+            metrics.viewers.clone(),
+        );
+        registry.register(
+            "messages_forwarded",
+            "Messages forwarded between the game and viewers",
+            metrics.messages.clone(),
+        );
+        registry.register_with_unit(
+            "forwarded",
+            "Bytes forwarded between the game and viewers",
+            Unit::Bytes,
+            metrics.bytes.clone(),
+        );
+        registry.register(
+            "messages_dropped",
+            "Viewer messages that were not forwarded to the game",
+            metrics.dropped.clone(),
+        );
+        registry.register(
+            "broadcast_lagged",
+            "Times a viewer fell too far behind the messages of the game",
+            metrics.lagged.clone(),
+        );
+        registry.register_with_unit(
+            "lobby_lifetime",
+            "How long lobbies were open",
+            Unit::Seconds,
+            metrics.lobby_lifetime.clone(),
+        );
+
+        Self {
+            registry: Arc::new(registry),
+            ..metrics
+        }
+    }
+}
+
+impl Metrics {
+    /// A lobby was created
+    pub fn lobby_opened(&self) {
+        self.lobbies.inc();
+    }
+
+    /// A lobby was removed after being open for `lifetime`
+    pub fn lobby_closed(&self, lifetime: Duration) {
+        self.lobbies.dec();
+        self.lobby_lifetime.observe(lifetime.as_secs_f64());
+    }
+
+    /// A lobby was created but never opened, it does not count towards the lifetimes
+    pub fn lobby_discarded(&self) {
+        self.lobbies.dec();
+    }
+
+    /// A streamer connected
+    pub fn streamer_connected(&self) {
+        self.streamers.inc();
+    }
+
+    /// A streamer disconnected
+    pub fn streamer_disconnected(&self) {
+        self.streamers.dec();
+    }
+
+    /// A viewer connected
+    pub fn viewer_joined(&self) {
+        self.viewers.inc();
+    }
+
+    /// A viewer disconnected
+    pub fn viewer_left(&self) {
+        self.viewers.dec();
+    }
+
+    /// A message of `len` bytes was forwarded
+    pub fn forwarded(&self, direction: Direction, len: usize) {
+        let label = [(
+            "direction",
+            match direction {
+                Direction::ToGame => "to_game",
+                Direction::ToViewer => "to_viewer",
+            },
+        )];
+        self.messages.get_or_create(&label).inc();
+        self.bytes
+            .get_or_create(&label)
+            .inc_by(u64::try_from(len).unwrap_or(u64::MAX));
+    }
+
+    /// A viewer message was dropped
+    pub fn dropped(&self, reason: DropReason) {
+        let reason = match reason {
+            DropReason::RateLimited => "rate_limited",
+            DropReason::TooLarge => "too_large",
+        };
+        self.dropped.get_or_create(&[("reason", reason)]).inc();
+    }
+
+    /// A viewer fell behind the broadcast
+    pub fn lagged(&self) {
+        self.lagged.inc();
+    }
+
+    /// Encode all metrics in the `OpenMetrics` text format
+    pub fn render(&self) -> Result<String, fmt::Error> {
+        let mut text = String::new();
+        encode(&mut text, &self.registry)?;
+        Ok(text)
+    }
+}
+
+/// Metrics for prometheus to scrape
+#[get("/metrics")]
+pub fn metrics(lobbies: &State<Lobbies>) -> Result<(ContentType, String), Errors> {
+    let content_type = ContentType::new("application", "openmetrics-text")
+        .with_params([("version", "1.0.0"), ("charset", "utf-8")]);
+    Ok((content_type, lobbies.metrics.render().unknown()?))
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use rocket::http::Status;
+    use rocket::local::blocking::Client;
+
+    use super::*;
+    use crate::tests::test_rocket;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.lobby_opened();
+        metrics.viewer_joined();
+        metrics.forwarded(Direction::ToGame, 10);
+        metrics.forwarded(Direction::ToGame, 5);
+        metrics.dropped(DropReason::TooLarge);
+        metrics.lobby_closed(Duration::from_secs(90));
+
+        let text = metrics.render().unwrap();
+        assert!(text.contains("minimap_lobbies 0\n"));
+        assert!(text.contains("minimap_viewers 1\n"));
+        assert!(text.contains("minimap_messages_forwarded_total{direction=\"to_game\"} 2\n"));
+        assert!(text.contains("minimap_forwarded_bytes_total{direction=\"to_game\"} 15\n"));
+        assert!(text.contains("minimap_messages_dropped_total{reason=\"too_large\"} 1\n"));
+        assert!(text.contains("minimap_lobby_lifetime_seconds_bucket{le=\"300.0\"} 1\n"));
+        assert!(text.contains("minimap_lobby_lifetime_seconds_bucket{le=\"60.0\"} 0\n"));
+    }
+
+    #[test]
+    fn endpoint() {
+        let client = Client::tracked(test_rocket()).unwrap();
+        client.post("/lobby/new?user=viv").dispatch();
+
+        let response = client.get("/metrics").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.content_type().map(|ty| ty.to_string()),
+            Some("application/openmetrics-text; version=1.0.0; charset=utf-8".into())
+        );
+        assert!(response
+            .into_string()
+            .unwrap()
+            .contains("minimap_lobbies 1\n"));
+    }
+
+    #[test]
+    fn lobby_removed() {
+        let client = Client::tracked(test_rocket()).unwrap();
+        let lobbies = client.rocket().state::<Lobbies>().unwrap();
+        client.post("/lobby/new?user=viv").dispatch();
+        lobbies.channels.write().unwrap().remove("viv");
+
+        let text = lobbies.metrics.render().unwrap();
+        assert!(text.contains("minimap_lobbies 0\n"));
+        assert!(text.contains("minimap_lobby_lifetime_seconds_count 1\n"));
+    }
+}
@@ -0,0 +1,123 @@
+//! Version and features of the messages a game sends
+//!
+//! The game declares its protocol when creating the lobby, viewers are told about it in the first
+//! message after connecting. Clients that say which version they speak are refused with a close
+//! frame if it is not the one of the game.
+
+use rocket::form;
+use rocket::futures::SinkExt;
+use rocket::serde::json::serde_json;
+use serde::{Deserialize, Serialize};
+use twitch_minimap_protocol::{Feature, Notice, Protocol, Version};
+
+/// Protocol a game declares when creating the lobby, as it is stored
+///
+/// Unlike [`Protocol`] this keeps the version as a number, so it can be read from the query.
+#[derive(FromForm, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Declaration {
+    /// Version of the message format, games that do not declare one speak the first
+    #[field(validate = known())]
+    #[field(default_with = Some(1))]
+    pub version: u32,
+    /// Optional messages the game sends, e.g. `css` or `reset`
+    pub features: Vec<String>,
+}
+
+impl Default for Declaration {
+    fn default() -> Self {
+        Self {
+            version: 1,
+            features: Vec::new(),
+        }
+    }
+}
+
+/// Refuses versions newer than the server, it could not know what they mean
+#[allow(clippy::trivially_copy_pass_by_ref)] // Rocket passes the field by reference
+fn known<'v>(version: &u32) -> form::Result<'v, ()> {
+    Version::try_from(*version).map(drop).map_err(|_| {
+        let latest = Version::LATEST;
+        form::Error::validation(format!("unknown version, the server speaks up to {latest}")).into()
+    })
+}
+
+impl Declaration {
+    /// The declared protocol
+    pub fn protocol(&self) -> Protocol {
+        Protocol {
+            version: Version::try_from(self.version).unwrap_or_default(),
+            features: self
+                .features
+                .iter()
+                .map(|feature| Feature::from(feature.as_str()))
+                .collect(),
+        }
+    }
+
+    /// Message telling a viewer which protocol the game speaks
+    pub fn hello(&self) -> Option<ws::Message> {
+        serde_json::to_string(&Notice::Protocol(self.protocol()))
+            .ok()
+            .map(ws::Message::Text)
+    }
+
+    /// Why a client speaking `version` can not talk to this game, clients that do not say which
+    /// version they speak are let in
+    pub fn mismatch(&self, version: Option<u32>) -> Option<String> {
+        let version = version?;
+        (version != self.version).then(|| {
+            format!(
+                "The game speaks protocol version {}, this client speaks version {version}",
+                self.version
+            )
+        })
+    }
+}
+
+/// Accept the websocket only to close it right away with `reason`
+///
+/// Browsers do not show the body of a refused upgrade, a close frame reaches the client.
+pub fn refuse(ws: ws::WebSocket, reason: String) -> ws::Channel<'static> {
+    ws.channel(move |mut connection| {
+        Box::pin(async move {
+            connection
+                .send(ws::Message::Close(Some(ws::frame::CloseFrame {
+                    code: ws::frame::CloseCode::Protocol,
+                    reason: reason.into(),
+                })))
+                .await
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+
+    #[test]
+    fn hello() {
+        let protocol = Declaration {
+            version: 1,
+            features: vec!["css".into(), "reset".into()],
+        };
+        assert_eq!(
+            protocol.hello(),
+            Some(ws::Message::Text(
+                r#"{"protocol":{"version":1,"features":["css","reset"]}}"#.into()
+            ))
+        );
+    }
+
+    #[test]
+    fn mismatch() {
+        let protocol = Declaration::default();
+        assert_eq!(protocol.mismatch(None), None);
+        assert_eq!(protocol.mismatch(Some(1)), None);
+        assert_eq!(
+            protocol.mismatch(Some(2)).unwrap(),
+            "The game speaks protocol version 1, this client speaks version 2"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+//! Urls handed out to clients, built from where the server is reachable from the outside
+
+use rocket::fairing::AdHoc;
+use serde::{Deserialize, Serialize};
+use twitch_minimap_protocol::{url, StreamerLogin};
+
+/// Websocket scheme clients should use
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    /// Plain websockets
+    #[default]
+    Ws,
+    /// Websockets over TLS
+    Wss,
+}
+
+/// Where clients can reach the server
+///
+/// Behind a TLS terminating proxy this is the address of the proxy, not of the server itself.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct PublicUrl {
+    /// Host (and port) clients connect to
+    #[serde(rename = "public_host")]
+    pub host: String,
+    /// Websocket scheme clients connect with
+    #[serde(rename = "public_scheme")]
+    pub scheme: Scheme,
+    /// Path the proxy serves the server under, e.g. `/minimap`
+    #[serde(rename = "public_path_prefix")]
+    pub path_prefix: String,
+}
+
+impl Default for PublicUrl {
+    fn default() -> Self {
+        Self {
+            host: "localhost:8000".into(),
+            scheme: Scheme::Ws,
+            path_prefix: String::new(),
+        }
+    }
+}
+
+impl PublicUrl {
+    /// Websocket url for `path`, which has to start with a `/`
+    pub fn websocket(&self, path: &str) -> String {
+        let scheme = match self.scheme {
+            Scheme::Ws => "ws",
+            Scheme::Wss => "wss",
+        };
+        let prefix = self.path_prefix.trim_matches('/');
+        if prefix.is_empty() {
+            format!("{scheme}://{}{path}", self.host)
+        } else {
+            format!("{scheme}://{}/{prefix}{path}", self.host)
+        }
+    }
+
+    /// Url the streamer of `user` connects to with `key`
+    pub fn streamer(&self, user: &str, key: &str) -> String {
+        self.websocket(&url::connect_streamer(user, key))
+    }
+
+    /// What the streamer of `user` needs to connect to their lobby with `key`
+    pub fn login(&self, user: &str, key: String) -> StreamerLogin {
+        StreamerLogin {
+            url: self.streamer(user, &key),
+            key,
+        }
+    }
+}
+
+/// Manages the [`PublicUrl`]
+pub fn fairing() -> AdHoc {
+    AdHoc::config::<PublicUrl>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default() {
+        assert_eq!(
+            PublicUrl::default().streamer("viv", "abc"),
+            "ws://localhost:8000/lobby/connect/streamer?user=viv&key=abc"
+        );
+    }
+
+    #[test]
+    fn behind_proxy() {
+        let url = PublicUrl {
+            host: "websocket.matissetec.dev".into(),
+            scheme: Scheme::Wss,
+            path_prefix: "minimap/".into(),
+        };
+        assert_eq!(
+            url.streamer("viv", "abc"),
+            "wss://websocket.matissetec.dev/minimap/lobby/connect/streamer?user=viv&key=abc"
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! Token buckets limiting how fast viewers can send messages to the game
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use rocket::form;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::ViewerIdentity;
+
+/// Who shares a bucket
+#[derive(FromFormField, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitPer {
+    /// Every connection has its own bucket
+    #[default]
+    Connection,
+    /// All connections of a viewer share a bucket, so opening more tabs does not help
+    User,
+}
+
+/// How many messages a viewer may send, set by the game when creating the lobby
+#[derive(FromForm, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Messages that can be sent at once, before the bucket is empty
+    #[field(validate = range(1..))]
+    #[field(default_with = Some(1))]
+    pub burst: u32,
+    /// Messages added back to the bucket per second
+    #[field(validate = positive())]
+    #[field(default_with = Some(50.0))]
+    pub per_second: f64,
+    /// Longest message in bytes, longer ones are dropped
+    #[field(validate = range(1..))]
+    #[field(default_with = Some(1000))]
+    pub max_size: usize,
+    /// Who shares a bucket
+    #[field(default_with = Some(LimitPer::Connection))]
+    pub per: LimitPer,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            burst: 1,
+            per_second: 50.0,
+            max_size: 1000,
+            per: LimitPer::Connection,
+        }
+    }
+}
+
+/// Refuses rates that would never refill the bucket
+#[allow(clippy::trivially_copy_pass_by_ref)] // Rocket passes the field by reference
+fn positive<'v>(value: &f64) -> form::Result<'v, ()> {
+    if *value > 0.0 {
+        Ok(())
+    } else {
+        Err(form::Error::validation("must be greater than zero").into())
+    }
+}
+
+/// Tokens left for a connection or user
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    /// Messages that can be sent right now, fractions refill over time
+    tokens: f64,
+    /// When `tokens` was last updated
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Refill the bucket up to `now`, then take a token if there is one
+    ///
+    /// Returns how long to wait for the next token if the bucket is empty.
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = limit
+            .per_second
+            .mul_add(elapsed, self.tokens)
+            .min(f64::from(limit.burst));
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - self.tokens) / limit.per_second;
+            Err(Duration::try_from_secs_f64(wait).unwrap_or(Duration::MAX))
+        }
+    }
+
+    /// Whether the bucket would be full at `now`, so forgetting it changes nothing
+    fn full(&self, limit: &RateLimit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        limit.per_second.mul_add(elapsed, self.tokens) >= f64::from(limit.burst)
+    }
+}
+
+/// The buckets of every viewer of a lobby
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    /// Limits of the lobby
+    limit: RateLimit,
+    /// Buckets by connection or user id, depending on [`RateLimit::per`]
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    /// Create the buckets for a new lobby
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Arc::default(),
+        }
+    }
+
+    /// Longest message viewers may send
+    pub const fn max_size(&self) -> usize {
+        self.limit.max_size
+    }
+
+    /// Key of the bucket `viewer` takes from
+    fn key<'a>(&self, viewer: &'a ViewerIdentity) -> &'a str {
+        match self.limit.per {
+            LimitPer::Connection => &viewer.connection_id,
+            LimitPer::User => viewer.user_id.as_deref().unwrap_or(&viewer.opaque_user_id),
+        }
+    }
+
+    /// Take a token for a message of `viewer` sent at `now`
+    ///
+    /// Returns how long to wait before sending again if the viewer is throttled.
+    pub fn check(&self, viewer: &ViewerIdentity, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        buckets
+            .entry(self.key(viewer).to_owned())
+            .or_insert(Bucket {
+                tokens: f64::from(self.limit.burst),
+                updated: now,
+            })
+            .take(&self.limit, now)
+    }
+
+    /// Drop the bucket of a disconnected viewer, unless it still holds back other connections
+    pub fn forget(&self, viewer: &ViewerIdentity) {
+        let now = Instant::now();
+        let key = self.key(viewer);
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let unused = self.limit.per == LimitPer::Connection
+            || buckets
+                .get(key)
+                .is_none_or(|bucket| bucket.full(&self.limit, now));
+        if unused {
+            buckets.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+    use crate::auth::Role;
+
+    fn viewer(connection_id: &str) -> ViewerIdentity {
+        ViewerIdentity {
+            opaque_user_id: "U1".into(),
+            user_id: Some("1".into()),
+            role: Role::Viewer,
+            connection_id: connection_id.into(),
+        }
+    }
+
+    fn limiter(per: LimitPer) -> RateLimiter {
+        RateLimiter::new(RateLimit {
+            burst: 2,
+            per_second: 4.0,
+            per,
+            ..RateLimit::default()
+        })
+    }
+
+    #[test]
+    fn burst_then_refill() {
+        let limiter = limiter(LimitPer::Connection);
+        let viewer = viewer("a");
+        let now = Instant::now();
+
+        assert_eq!(limiter.check(&viewer, now), Ok(()));
+        assert_eq!(limiter.check(&viewer, now), Ok(()));
+        assert_eq!(limiter.check(&viewer, now), Err(Duration::from_millis(250)));
+
+        let later = now + Duration::from_millis(250);
+        assert_eq!(limiter.check(&viewer, later), Ok(()));
+        assert!(limiter.check(&viewer, later).is_err());
+    }
+
+    #[test]
+    fn per_connection() {
+        let limiter = limiter(LimitPer::Connection);
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            limiter.check(&viewer("a"), now).unwrap();
+        }
+        assert!(limiter.check(&viewer("a"), now).is_err());
+        assert_eq!(limiter.check(&viewer("b"), now), Ok(()));
+    }
+
+    #[test]
+    fn per_user() {
+        let limiter = limiter(LimitPer::User);
+        let now = Instant::now();
+
+        limiter.check(&viewer("a"), now).unwrap();
+        limiter.check(&viewer("b"), now).unwrap();
+        assert!(limiter.check(&viewer("c"), now).is_err());
+
+        // Reconnecting does not refill the bucket
+        limiter.forget(&viewer("a"));
+        assert!(limiter.check(&viewer("a"), now).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+//! Background task closing lobbies that are no longer in use
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::AdHoc;
+use rocket::tokio;
+use serde::Deserialize;
+
+use crate::registry::LobbyRecord;
+use crate::{Lobbies, Lobby, StreamerState, UserId};
+
+/// How long lobbies may sit unused, all values are in seconds
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct ReaperConfig {
+    /// How often to check for expired lobbies
+    pub reaper_interval: u64,
+    /// Lobby was created, but no streamer is connected
+    pub unconnected_lobby_ttl: u64,
+    /// Streamer disconnected and has not resumed
+    pub streamer_grace_period: u64,
+    /// Streamer is connected, but hasnt sent anything
+    pub idle_streamer_ttl: u64,
+    /// No viewer has been connected
+    pub empty_lobby_ttl: u64,
+}
+
+impl Default for ReaperConfig {
+    fn default() -> Self {
+        Self {
+            reaper_interval: 10,
+            unconnected_lobby_ttl: 60,
+            streamer_grace_period: 30,
+            idle_streamer_ttl: 120,
+            empty_lobby_ttl: 60 * 60,
+        }
+    }
+}
+
+/// Why a lobby was reaped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReapReason {
+    /// No streamer connected in time
+    Unconnected,
+    /// The streamer disconnected and did not resume in time
+    StreamerGone,
+    /// The streamer stopped sending messages
+    IdleStreamer,
+    /// No viewers were connected for too long
+    Empty,
+}
+
+impl fmt::Display for ReapReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Unconnected => "no streamer connected",
+            Self::StreamerGone => "streamer did not reconnect",
+            Self::IdleStreamer => "streamer stopped sending messages",
+            Self::Empty => "no viewers connected",
+        })
+    }
+}
+
+impl ReaperConfig {
+    /// Check if `lobby` has expired at `now`
+    fn expired(&self, lobby: &Lobby, now: Instant) -> Option<ReapReason> {
+        let activity = lobby.activity.get()?;
+        let since = |instant: Instant| now.saturating_duration_since(instant);
+        let streamer = *lobby.streamer.borrow();
+        if lobby.mirror && !matches!(streamer, StreamerState::Connected(_)) {
+            // The game is elsewhere, the copy goes once the lobby is withdrawn or its record
+            // expires
+            return None;
+        }
+        match streamer {
+            StreamerState::Waiting => {
+                return (since(activity.streamer_seen) >= secs(self.unconnected_lobby_ttl))
+                    .then_some(ReapReason::Unconnected);
+            }
+            StreamerState::Disconnected(at) => {
+                // Copies of lobbies whose game is on another instance hear from it through messages
+                return (since(at.max(activity.streamer_seen)) >= secs(self.streamer_grace_period))
+                    .then_some(ReapReason::StreamerGone);
+            }
+            StreamerState::Connected(_) => {}
+        }
+        if since(activity.streamer_seen) >= secs(self.idle_streamer_ttl) {
+            return Some(ReapReason::IdleStreamer);
+        }
+        // With a shared lobby backend the viewers may be connected to other instances
+        let viewers = activity.viewers.max(activity.announced_viewers);
+        if viewers == 0 && since(activity.viewers_seen) >= secs(self.empty_lobby_ttl) {
+            return Some(ReapReason::Empty);
+        }
+        None
+    }
+
+    /// Close and remove all expired lobbies
+    ///
+    /// Returns the reaped lobbies that were created on this instance and the reason, the other
+    /// instances have to be told to close them too.
+    fn sweep(&self, lobbies: &Lobbies, now: Instant) -> Vec<(UserId, String)> {
+        let Ok(mut channels) = lobbies.channels.write() else {
+            log::error!("Lobbies were poisoned, can not reap");
+            return Vec::new();
+        };
+        let mut reaped = Vec::new();
+
+        channels.retain(|user, lobby| {
+            let Some(reason) = self.expired(lobby, now) else {
+                return true;
+            };
+            log::info!(
+                "Reaping lobby of {user} after {}s: {reason}",
+                now.saturating_duration_since(lobby.created).as_secs()
+            );
+            lobby.close(reason.to_string());
+            lobbies.registry.remove(user);
+            if !lobby.mirror {
+                reaped.push((Arc::clone(user), reason.to_string()));
+            }
+            false
+        });
+        reaped
+    }
+}
+
+/// Close the copies of lobbies whose record expired, their instance went away without
+/// withdrawing them
+async fn sweep_orphans(lobbies: &Lobbies) {
+    let copies = lobbies.channels.read().map_or_else(
+        |_| Vec::new(),
+        |channels| {
+            channels
+                .iter()
+                .filter(|(_, lobby)| {
+                    lobby.mirror && !matches!(*lobby.streamer.borrow(), StreamerState::Connected(_))
+                })
+                .map(|(owner, lobby)| (Arc::clone(owner), lobby.closed.subscribe()))
+                .collect::<Vec<_>>()
+        },
+    );
+    for (owner, closed) in copies {
+        if lobbies.backend.find(&owner).await.is_none() {
+            lobbies.closed_elsewhere(&owner, &closed, "The lobby is gone".into());
+        }
+    }
+}
+
+/// Records of the lobbies this instance keeps announced
+///
+/// These are the lobbies it created or restored, and copies whose game is connected here.
+fn looked_after(lobbies: &Lobbies) -> Vec<LobbyRecord> {
+    let Ok(channels) = lobbies.channels.read() else {
+        return Vec::new();
+    };
+    channels
+        .values()
+        .filter(|lobby| {
+            !lobby.mirror || matches!(*lobby.streamer.borrow(), StreamerState::Connected(_))
+        })
+        .map(Lobby::record)
+        .collect()
+}
+
+/// Seconds to a duration
+const fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+}
+
+/// Periodically sweeps [`Lobbies`] for expired lobbies until shutdown
+pub fn fairing() -> AdHoc {
+    AdHoc::on_liftoff("Lobby reaper", |rocket| {
+        Box::pin(async move {
+            let config = match rocket.figment().extract::<ReaperConfig>() {
+                Ok(config) => config,
+                Err(err) => {
+                    log::error!("Invalid reaper config, using defaults: {err}");
+                    ReaperConfig::default()
+                }
+            };
+            let Some(lobbies) = rocket.state::<Lobbies>().cloned() else {
+                log::error!("Lobbies are not managed, reaper not started");
+                return;
+            };
+            let mut shutdown = rocket.shutdown();
+
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(secs(config.reaper_interval.max(1)));
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {
+                            for (user, reason) in config.sweep(&lobbies, Instant::now()) {
+                                lobbies.backend.withdraw(&user, &reason).await;
+                            }
+                            for record in looked_after(&lobbies) {
+                                lobbies.backend.refresh(&record).await;
+                            }
+                            sweep_orphans(&lobbies).await;
+                        },
+                        () = &mut shutdown => break,
+                    }
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use twitch_minimap_protocol::Presence;
+
+    use super::*;
+    use crate::metrics::Metrics;
+    use crate::LobbyOptions;
+
+    const CONFIG: ReaperConfig = ReaperConfig {
+        reaper_interval: 1,
+        unconnected_lobby_ttl: 10,
+        streamer_grace_period: 5,
+        idle_streamer_ttl: 20,
+        empty_lobby_ttl: 30,
+    };
+
+    fn connect(lobby: &Lobby) {
+        lobby
+            .streamer
+            .send_replace(StreamerState::Connected(uuid::Uuid::new_v4()));
+    }
+
+    #[test]
+    fn fresh() {
+        let lobby = Lobby::new(
+            Arc::from("viv"),
+            LobbyOptions::default(),
+            Metrics::default(),
+        );
+        assert_eq!(CONFIG.expired(&lobby, Instant::now()), None);
+    }
+
+    #[test]
+    fn unconnected() {
+        let lobby = Lobby::new(
+            Arc::from("viv"),
+            LobbyOptions::default(),
+            Metrics::default(),
+        );
+        let later = lobby.created + secs(10);
+
+        assert_eq!(CONFIG.expired(&lobby, later), Some(ReapReason::Unconnected));
+    }
+
+    #[test]
+    fn streamer_gone() {
+        let lobby = Lobby::new(
+            Arc::from("viv"),
+            LobbyOptions::default(),
+            Metrics::default(),
+        );
+        let now = Instant::now();
+        lobby
+            .streamer
+            .send_replace(StreamerState::Disconnected(now));
+
+        assert_eq!(CONFIG.expired(&lobby, now + secs(4)), None);
+        assert_eq!(
+            CONFIG.expired(&lobby, now + secs(5)),
+            Some(ReapReason::StreamerGone)
+        );
+    }
+
+    #[test]
+    fn idle_streamer() {
+        let lobby = Lobby::new(
+            Arc::from("viv"),
+            LobbyOptions::default(),
+            Metrics::default(),
+        );
+        connect(&lobby);
+        lobby.activity.viewer_joined();
+        let activity = lobby.activity.get().unwrap();
+
+        assert_eq!(
+            CONFIG.expired(&lobby, activity.streamer_seen + secs(19)),
+            None
+        );
+        assert_eq!(
+            CONFIG.expired(&lobby, activity.streamer_seen + secs(20)),
+            Some(ReapReason::IdleStreamer)
+        );
+    }
+
+    #[test]
+    fn empty() {
+        let lobby = Lobby::new(
+            Arc::from("viv"),
+            LobbyOptions::default(),
+            Metrics::default(),
+        );
+        connect(&lobby);
+        lobby.activity.viewer_joined();
+        lobby.activity.viewer_left();
+        let later = lobby.activity.get().unwrap().viewers_seen + secs(30);
+        lobby
+            .activity
+            .update(|activity| activity.streamer_seen = later);
+
+        assert_eq!(CONFIG.expired(&lobby, later), Some(ReapReason::Empty));
+
+        lobby.activity.viewer_joined();
+        assert_eq!(CONFIG.expired(&lobby, later), None);
+    }
+
+    #[test]
+    fn announced_viewers_keep_lobby() {
+        let lobby = Lobby::new(
+            Arc::from("viv"),
+            LobbyOptions::default(),
+            Metrics::default(),
+        );
+        connect(&lobby);
+        let viewer = twitch_minimap_protocol::Viewer {
+            opaque_user_id: "U1".into(),
+            user_id: None,
+            role: twitch_minimap_protocol::Role::Viewer,
+            connection_id: "a".into(),
+        };
+        lobby.activity.announced(&Presence::ViewerJoined {
+            viewer: viewer.clone(),
+        });
+        let later = lobby.activity.get().unwrap().viewers_seen + secs(30);
+        lobby
+            .activity
+            .update(|activity| activity.streamer_seen = later);
+
+        assert_eq!(CONFIG.expired(&lobby, later), None);
+
+        lobby.activity.announced(&Presence::ViewerLeft { viewer });
+        let later = lobby.activity.get().unwrap().viewers_seen + secs(30);
+        lobby
+            .activity
+            .update(|activity| activity.streamer_seen = later);
+        assert_eq!(CONFIG.expired(&lobby, later), Some(ReapReason::Empty));
+    }
+
+    #[test]
+    fn copies_wait_for_owner() {
+        let mut lobby = Lobby::new(
+            Arc::from("viv"),
+            LobbyOptions::default(),
+            Metrics::default(),
+        );
+        lobby.mirror = true;
+        let now = Instant::now();
+        lobby
+            .streamer
+            .send_replace(StreamerState::Disconnected(now));
+        assert_eq!(CONFIG.expired(&lobby, now + secs(3600)), None);
+
+        // Once the game is connected to the copy, it is looked after here
+        connect(&lobby);
+        assert_eq!(
+            CONFIG.expired(&lobby, now + secs(3600)),
+            Some(ReapReason::IdleStreamer)
+        );
+    }
+
+    #[test]
+    fn sweep_closes() {
+        let lobbies = Lobbies::default();
+        let lobby = Lobby::new(
+            Arc::from("viv"),
+            LobbyOptions::default(),
+            Metrics::default(),
+        );
+        let closed = lobby.closed.subscribe();
+        let later = lobby.created + secs(10);
+        lobbies
+            .channels
+            .write()
+            .unwrap()
+            .insert(Arc::from("viv"), lobby);
+
+        let reaped = CONFIG.sweep(&lobbies, later);
+
+        assert_eq!(reaped, [(Arc::from("viv"), "no streamer connected".into())]);
+        assert!(lobbies.channels.read().unwrap().is_empty());
+        assert_eq!(closed.borrow().as_deref(), Some("no streamer connected"));
+    }
+}
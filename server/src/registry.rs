@@ -0,0 +1,184 @@
+//! Keeps lobbies on disk, so games can resume them after the server restarts
+
+use std::path::{Path, PathBuf};
+
+use rocket::fairing::AdHoc;
+use rocket::serde::json::serde_json;
+use serde::{Deserialize, Serialize};
+
+use crate::{Lobbies, LobbyOptions};
+
+/// Where lobbies are stored
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct RegistryConfig {
+    /// Directory of the lobby store, lobbies are only kept in memory if this is not set
+    lobby_store: Option<PathBuf>,
+}
+
+/// Everything needed to bring a lobby back
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LobbyRecord {
+    /// Streamer who created the lobby
+    pub owner: String,
+    /// Key the streamer resumes with
+    pub streamer_key: String,
+    /// When the lobby was created, as a unix timestamp
+    pub created_at: u64,
+    /// Settings chosen by the game
+    pub options: LobbyOptions,
+}
+
+/// Store of the lobbies by owner, does nothing if no store is configured
+#[derive(Debug, Clone, Default)]
+pub struct Registry(Option<sled::Db>);
+
+impl Registry {
+    /// Open the store at `path`, creating it if needed
+    pub fn open(path: &Path) -> sled::Result<Self> {
+        sled::open(path).map(|db| Self(Some(db)))
+    }
+
+    /// Store `record`, replacing an older record of the same owner
+    pub fn save(&self, record: &LobbyRecord) {
+        let Some(db) = &self.0 else {
+            return;
+        };
+        let res = serde_json::to_vec(record)
+            .map_err(|err| err.to_string())
+            .and_then(|value| {
+                db.insert(record.owner.as_bytes(), value)
+                    .and_then(|_| db.flush())
+                    .map_err(|err| err.to_string())
+            });
+        if let Err(err) = res {
+            log::error!("Could not store lobby of {}: {err}", record.owner);
+        }
+    }
+
+    /// Forget the lobby of `owner`
+    pub fn remove(&self, owner: &str) {
+        let Some(db) = &self.0 else {
+            return;
+        };
+        if let Err(err) = db.remove(owner.as_bytes()).and_then(|_| db.flush()) {
+            log::error!("Could not remove stored lobby of {owner}: {err}");
+        }
+    }
+
+    /// Every stored lobby, records that can not be read are skipped
+    pub fn load(&self) -> Vec<LobbyRecord> {
+        let Some(db) = &self.0 else {
+            return Vec::new();
+        };
+        db.iter()
+            .filter_map(|entry| {
+                let (owner, value) = entry
+                    .map_err(|err| log::error!("Could not read lobby store: {err}"))
+                    .ok()?;
+                serde_json::from_slice(&value)
+                    .map_err(|err| {
+                        let owner = String::from_utf8_lossy(&owner);
+                        log::warn!("Skipping unreadable stored lobby of {owner}: {err}");
+                    })
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+/// Opens the lobby store and backend, and manages [`Lobbies`] with the lobbies restored from it
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Lobby registry", |rocket| async move {
+        let config = match rocket.figment().extract::<RegistryConfig>() {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!("Invalid lobby store config: {err}");
+                return Err(rocket);
+            }
+        };
+        let registry = match config.lobby_store {
+            Some(path) => match Registry::open(&path) {
+                Ok(registry) => registry,
+                Err(err) => {
+                    log::error!("Could not open lobby store {}: {err}", path.display());
+                    return Err(rocket);
+                }
+            },
+            None => Registry::default(),
+        };
+
+        let backend = match crate::backend::open(rocket.figment()).await {
+            Ok(backend) => backend,
+            Err(err) => {
+                log::error!("{err}");
+                return Err(rocket);
+            }
+        };
+
+        let lobbies = Lobbies::restore(registry, backend).await;
+        Ok(rocket.manage(lobbies))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+
+    fn temporary() -> Registry {
+        Registry(Some(sled::Config::new().temporary(true).open().unwrap()))
+    }
+
+    fn record(owner: &str) -> LobbyRecord {
+        LobbyRecord {
+            owner: owner.into(),
+            streamer_key: format!("key of {owner}"),
+            created_at: 1_728_000_000,
+            options: LobbyOptions::default(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let registry = temporary();
+        registry.save(&record("viv"));
+        registry.save(&record("alice"));
+        registry.remove("alice");
+
+        assert_eq!(registry.load(), [record("viv")]);
+    }
+
+    #[test]
+    fn replace() {
+        let registry = temporary();
+        registry.save(&record("viv"));
+        let rotated = LobbyRecord {
+            streamer_key: "rotated".into(),
+            ..record("viv")
+        };
+        registry.save(&rotated);
+
+        assert_eq!(registry.load(), [rotated]);
+    }
+
+    #[test]
+    fn before_protocol() {
+        let registry = temporary();
+        let Some(db) = &registry.0 else { return };
+        let stored = r#"{"owner":"viv","streamer_key":"key of viv","created_at":1728000000,
+            "options":{"lag":"resync","limit":{"burst":1,"per_second":50.0,"max_size":1000,"per":"connection"}}}"#;
+        db.insert("viv", stored).unwrap();
+
+        assert_eq!(registry.load(), [record("viv")]);
+    }
+
+    #[test]
+    fn disabled() {
+        let registry = Registry::default();
+        registry.save(&record("viv"));
+
+        assert_eq!(registry.load(), []);
+    }
+}
@@ -0,0 +1,204 @@
+//! Remembers the latest state messages of the game, so viewers joining late can be caught up
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+use rocket::serde::json::Value;
+use rocket::tokio::sync::broadcast;
+
+use crate::encoding::{decode, SharedMessage};
+
+/// How many sticky messages a lobby keeps, the oldest is dropped when a new key goes over this
+const MAX_STICKY: usize = 32;
+
+/// Key the latest message of `message`s kind is remembered under, if it should be
+///
+/// Css, units and reset messages are sticky by default, games can make any other message sticky
+/// by adding a top level `"sticky": "some key"`.
+pub fn sticky_key(message: &ws::Message) -> Option<String> {
+    let Value::Object(message) = decode(message)? else {
+        return None;
+    };
+
+    if let Some(Value::String(key)) = message.get("sticky") {
+        return Some(key.clone());
+    }
+    match message.get("data")? {
+        Value::Array(_) => Some("units".into()),
+        Value::Object(data) if data.contains_key("css") => Some("css".into()),
+        Value::Object(data) if data.contains_key("reset") => Some("reset".into()),
+        _ => None,
+    }
+}
+
+/// Latest sticky messages, ordered by when they were sent
+#[derive(Debug, Default)]
+struct Snapshot(Vec<(String, SharedMessage)>);
+
+impl Snapshot {
+    /// Remember `message`, replacing the last one with the same key
+    fn record(&mut self, key: String, message: SharedMessage) {
+        self.0.retain(|(existing, _)| *existing != key);
+        if self.0.len() >= MAX_STICKY {
+            self.0.remove(0);
+        }
+        self.0.push((key, message));
+    }
+
+    /// Put `older` messages in front, unless a message with the same key was recorded since
+    fn seed(&mut self, older: Vec<(String, ws::Message)>) {
+        let mut seeded = older
+            .into_iter()
+            .filter(|(key, _)| self.0.iter().all(|(existing, _)| existing != key))
+            .map(|(key, message)| (key, message.into()))
+            .collect::<Vec<_>>();
+        seeded.append(&mut self.0);
+        let excess = seeded.len().saturating_sub(MAX_STICKY);
+        seeded.drain(..excess);
+        self.0 = seeded;
+    }
+
+    /// The messages to replay, oldest first
+    fn messages(&self) -> Vec<SharedMessage> {
+        self.0.iter().map(|(_, message)| message.clone()).collect()
+    }
+}
+
+/// Broadcast from the game to the viewers, which remembers the sticky messages
+#[derive(Debug, Clone)]
+pub struct StickyBroadcast {
+    /// The live messages
+    sender: broadcast::Sender<SharedMessage>,
+    /// Sticky messages sent so far
+    snapshot: Arc<Mutex<Snapshot>>,
+}
+
+impl StickyBroadcast {
+    /// Create a broadcast holding up to `capacity` messages per receiver
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            sender: broadcast::channel(capacity).0,
+            snapshot: Arc::default(),
+        }
+    }
+
+    /// Send `message` to all viewers
+    pub fn send(&self, message: ws::Message) {
+        let key = sticky_key(&message);
+        let message = SharedMessage::from(message);
+        // The lock is held while sending, so a subscriber sees each message exactly once
+        let mut snapshot = self.snapshot.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(key) = key {
+            snapshot.record(key, message.clone());
+        }
+        let _ = self.sender.send(message);
+    }
+
+    /// Catch up on sticky messages sent before this copy of the lobby existed, oldest first
+    pub fn seed(&self, older: Vec<(String, ws::Message)>) {
+        let mut snapshot = self.snapshot.lock().unwrap_or_else(PoisonError::into_inner);
+        snapshot.seed(older);
+    }
+
+    /// Subscribe to the live messages and get the sticky messages sent before
+    pub fn subscribe(&self) -> (broadcast::Receiver<SharedMessage>, Vec<SharedMessage>) {
+        let snapshot = self.snapshot.lock().unwrap_or_else(PoisonError::into_inner);
+        (self.sender.subscribe(), snapshot.messages())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+
+    fn text(text: &str) -> ws::Message {
+        ws::Message::Text(text.into())
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(
+            sticky_key(&text(r#"{"data": []}"#)).as_deref(),
+            Some("units")
+        );
+        assert_eq!(
+            sticky_key(&text(r#"{"data": {"css": ""}}"#)).as_deref(),
+            Some("css")
+        );
+        assert_eq!(
+            sticky_key(&text(r#"{"data": {"reset": null}}"#)).as_deref(),
+            Some("reset")
+        );
+        assert_eq!(
+            sticky_key(&text(r#"{"sticky": "score", "data": 3}"#)).as_deref(),
+            Some("score")
+        );
+        assert_eq!(sticky_key(&text(r#"{"data": {"other": 1}}"#)), None);
+        assert_eq!(sticky_key(&text("not json")), None);
+        assert_eq!(sticky_key(&ws::Message::Binary(vec![1, 2])), None);
+
+        let msgpack = rmp_serde::to_vec_named(&Value::from_iter([("data", Vec::<u8>::new())]));
+        assert_eq!(
+            sticky_key(&ws::Message::Binary(msgpack.unwrap())).as_deref(),
+            Some("units")
+        );
+    }
+
+    #[test]
+    fn replays_latest_in_order() {
+        let broadcast = StickyBroadcast::new(10);
+        broadcast.send(text(r#"{"data": {"css": "a"}}"#));
+        broadcast.send(text(r#"{"data": [1]}"#));
+        broadcast.send(text(r#"{"data": {"other": 1}}"#));
+        broadcast.send(text(r#"{"data": {"css": "b"}}"#));
+
+        let (_, snapshot) = broadcast.subscribe();
+        assert_eq!(
+            snapshot,
+            [text(r#"{"data": [1]}"#), text(r#"{"data": {"css": "b"}}"#)]
+        );
+    }
+
+    #[test]
+    fn live_after_snapshot() {
+        let broadcast = StickyBroadcast::new(10);
+        broadcast.send(text(r#"{"data": []}"#));
+        let (mut live, snapshot) = broadcast.subscribe();
+        broadcast.send(text(r#"{"data": [1]}"#));
+
+        assert_eq!(snapshot, [text(r#"{"data": []}"#)]);
+        assert_eq!(live.try_recv().unwrap(), text(r#"{"data": [1]}"#));
+    }
+
+    #[test]
+    fn bounded() {
+        let broadcast = StickyBroadcast::new(10);
+        for i in 0..=MAX_STICKY {
+            broadcast.send(text(&format!(r#"{{"sticky": "{i}"}}"#)));
+        }
+
+        let (_, snapshot) = broadcast.subscribe();
+        assert_eq!(snapshot.len(), MAX_STICKY);
+        assert_eq!(snapshot[0], text(r#"{"sticky": "1"}"#));
+    }
+
+    #[test]
+    fn seed_keeps_newer() {
+        let broadcast = StickyBroadcast::new(10);
+        broadcast.send(text(r#"{"data": {"css": "new"}}"#));
+        broadcast.seed(vec![
+            ("units".into(), text(r#"{"data": [1]}"#)),
+            ("css".into(), text(r#"{"data": {"css": "old"}}"#)),
+        ]);
+
+        let (_, snapshot) = broadcast.subscribe();
+        assert_eq!(
+            snapshot,
+            [
+                text(r#"{"data": [1]}"#),
+                text(r#"{"data": {"css": "new"}}"#)
+            ]
+        );
+    }
+}
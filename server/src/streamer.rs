@@ -0,0 +1,136 @@
+//! Forwarding between the connected game and the viewers
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::tokio::sync::{mpsc, watch, Mutex};
+use twitch_minimap_protocol::CloseReason;
+use ws::stream::DuplexStream;
+
+use crate::backend::{LobbyLink, Route};
+use crate::encoding::Encoding;
+use crate::heartbeat::{Beat, Heartbeat, HeartbeatConfig};
+use crate::metrics::Metrics;
+use crate::{close_frame, ActivityTracker, StreamerState, UserId};
+
+/// Everything a streamer connection needs from its lobby
+#[derive(Debug)]
+pub struct StreamerSession {
+    /// Owner of the lobby
+    pub user: UserId,
+    /// Identifies this connection in the [`StreamerState`]
+    pub connection_id: uuid::Uuid,
+    /// Messages from the viewers, shared with replaced connections
+    pub inbox: Arc<Mutex<mpsc::Receiver<ws::Message>>>,
+    /// Messages to the viewers, wherever they are connected
+    pub to_viewers: LobbyLink,
+    /// Connection state of the streamer
+    pub streamer: watch::Sender<StreamerState>,
+    /// Activity of the lobby
+    pub activity: ActivityTracker,
+    /// Reason the lobby was closed, once it is
+    pub closed: watch::Receiver<Option<String>>,
+    /// Metrics of the lobby
+    pub metrics: Metrics,
+    /// How to ping the game
+    pub heartbeat: HeartbeatConfig,
+    /// What the game wants to receive
+    pub encoding: Encoding,
+}
+
+impl StreamerSession {
+    /// Forward messages in both directions until the connection closes or is replaced
+    pub async fn run(self, mut connection: DuplexStream) -> ws::result::Result<()> {
+        let Self {
+            user,
+            connection_id,
+            inbox,
+            to_viewers,
+            streamer,
+            activity,
+            mut closed,
+            metrics,
+            heartbeat,
+            encoding,
+        } = self;
+        // Only now that the websocket is open, a failed upgrade leaves the lobby as it was
+        match streamer.send_replace(StreamerState::Connected(connection_id)) {
+            StreamerState::Waiting => {}
+            StreamerState::Connected(_) => {
+                log::info!("Streamer replaced connection to lobby of {user}");
+            }
+            StreamerState::Disconnected(_) => log::info!("Streamer resumed lobby of {user}"),
+        }
+        let mut streamer_recv = streamer.subscribe();
+        let mut heartbeat = Heartbeat::new(heartbeat);
+
+        // Wait for a replaced connection to let go of the inbox
+        let mut channel_recv = inbox.lock().await;
+        metrics.streamer_connected();
+
+        loop {
+            rocket::tokio::select! {
+                res = connection.next() => {
+                    if let Some(Ok(message)) = res {
+                        heartbeat.last_seen().seen();
+                        // Pings and pongs are between us and the game, not for the viewers
+                        if message.is_text() || message.is_binary() {
+                            activity.streamer_seen();
+                            to_viewers.send(Route::Viewers, message).await;
+                        }
+                    } else {
+                        log::info!("STREAM: Websocket closed");
+                        break;
+                    }
+                },
+                beat = heartbeat.tick() => {
+                    if beat == Beat::Dead {
+                        log::info!("STREAM: Connection timed out");
+                        break;
+                    }
+                    let _ = connection.send(ws::Message::Ping(Vec::new())).await;
+                },
+                res = channel_recv.recv() => {
+                    if let Some(message) = res {
+                        let _ = connection.send(encoding.convert(message)).await;
+                    }
+                },
+                _ = closed.changed() => {
+                    log::info!("STREAM: Lobby was closed");
+                    let reason = closed.borrow().clone().unwrap_or_default();
+                    let closed = close_frame(CloseReason::LobbyClosed.code(), reason);
+                    let _ = connection.send(closed).await;
+                    break;
+                },
+                res = streamer_recv.changed() => {
+                    let state = *streamer_recv.borrow();
+                    if res.is_err() || state != StreamerState::Connected(connection_id) {
+                        log::info!("STREAM: Connection was replaced");
+                        let (code, reason) = if matches!(state, StreamerState::Connected(_)) {
+                            (CloseReason::TakenOver, "Another connection took over the lobby")
+                        } else {
+                            (CloseReason::KeyRotated, "Streamer key was rotated")
+                        };
+                        let _ = connection.send(close_frame(code.code(), reason.into())).await;
+                        break;
+                    }
+                },
+            }
+        }
+
+        metrics.streamer_disconnected();
+        let disconnected = streamer.send_if_modified(|state| {
+            let current = *state == StreamerState::Connected(connection_id);
+            if current {
+                *state = StreamerState::Disconnected(Instant::now());
+            }
+            current
+        });
+        if disconnected {
+            log::info!("Streamer disconnected from lobby of {user}, waiting for resume");
+        }
+
+        Ok(())
+    }
+}
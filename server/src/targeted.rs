@@ -0,0 +1,165 @@
+//! Messages the game addresses to a single viewer instead of everyone
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use rocket::serde::json::Value;
+use rocket::tokio::sync::mpsc;
+
+use crate::auth::ViewerIdentity;
+use crate::encoding::{decode, encode_like};
+
+/// How many addressed messages may wait for a viewer before new ones are dropped
+const VIEWER_QUEUE: usize = 32;
+
+/// Split an addressed message `{"to": "...", ...}` into the address and the message without it
+///
+/// The message keeps its encoding.
+pub fn addressed(message: &ws::Message) -> Option<(String, ws::Message)> {
+    // Most messages are not addressed, dont parse them just to find out
+    let maybe = match message {
+        ws::Message::Text(text) => text.contains("\"to\""),
+        // The key as a msgpack string
+        ws::Message::Binary(bytes) => bytes.windows(3).any(|key| key == b"\xa2to"),
+        _ => false,
+    };
+    if !maybe {
+        return None;
+    }
+    let Value::Object(mut fields) = decode(message)? else {
+        return None;
+    };
+    let Value::String(to) = fields.remove("to")? else {
+        return None;
+    };
+    Some((to, encode_like(message, &fields)?))
+}
+
+/// A connected viewer that can be addressed
+#[derive(Debug)]
+struct Entry {
+    /// Opaque id of the viewer
+    opaque_user_id: String,
+    /// Twitch id of the viewer, if shared
+    user_id: Option<String>,
+    /// Queue of the viewers connection
+    sender: mpsc::Sender<ws::Message>,
+}
+
+/// Senders to every connected viewer of a lobby, by connection id
+#[derive(Debug, Clone, Default)]
+pub struct ViewerDirectory(Arc<Mutex<HashMap<String, Entry>>>);
+
+impl ViewerDirectory {
+    /// Add a viewer, the returned receiver gets the messages addressed to it
+    ///
+    /// The returned sender reaches the same queue, for replies of the server itself.
+    pub fn register(
+        &self,
+        viewer: &ViewerIdentity,
+    ) -> (mpsc::Sender<ws::Message>, mpsc::Receiver<ws::Message>) {
+        let (sender, receiver) = mpsc::channel(VIEWER_QUEUE);
+        let entry = Entry {
+            opaque_user_id: viewer.opaque_user_id.clone(),
+            user_id: viewer.user_id.clone(),
+            sender: sender.clone(),
+        };
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(viewer.connection_id.clone(), entry);
+        (sender, receiver)
+    }
+
+    /// Remove a viewer once it disconnected
+    pub fn unregister(&self, viewer: &ViewerIdentity) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&viewer.connection_id);
+    }
+
+    /// Send `message` to every connection matching `to`, returns how many got it
+    ///
+    /// `to` may be a connection id, an opaque user id or a user id, a viewer can have multiple
+    /// connections open.
+    pub fn send(&self, to: &str, message: &ws::Message) -> usize {
+        let viewers = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut sent = 0;
+        for (connection_id, entry) in viewers.iter() {
+            let matches = *connection_id == to
+                || entry.opaque_user_id == to
+                || entry.user_id.as_deref() == Some(to);
+            if !matches {
+                continue;
+            }
+            if entry.sender.try_send(message.clone()).is_ok() {
+                sent += 1;
+            } else {
+                log::info!("Dropped message addressed to {connection_id}, queue is full");
+            }
+        }
+        sent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use rocket::serde::json::json;
+
+    use super::*;
+    use crate::auth::Role;
+
+    fn viewer(connection_id: &str, user_id: Option<&str>) -> ViewerIdentity {
+        ViewerIdentity {
+            opaque_user_id: format!("U{}", user_id.unwrap_or("anon")),
+            user_id: user_id.map(Into::into),
+            role: Role::Viewer,
+            connection_id: connection_id.into(),
+        }
+    }
+
+    fn text(text: &str) -> ws::Message {
+        ws::Message::Text(text.into())
+    }
+
+    #[test]
+    fn parse() {
+        let (to, message) = addressed(&text(r#"{"to": "abc", "data": 1}"#)).unwrap();
+        assert_eq!(to, "abc");
+        assert_eq!(message, text(r#"{"data":1}"#));
+
+        assert_eq!(addressed(&text(r#"{"data": {"to": "abc"}}"#)), None);
+        assert_eq!(addressed(&text(r#"{"to": 1, "data": 1}"#)), None);
+        assert_eq!(addressed(&text(r#"{"data": 1}"#)), None);
+
+        let msgpack = |value: Value| ws::Message::Binary(rmp_serde::to_vec_named(&value).unwrap());
+        let (to, message) = addressed(&msgpack(json!({"to": "abc", "data": 1}))).unwrap();
+        assert_eq!(to, "abc");
+        assert_eq!(message, msgpack(json!({"data": 1})));
+    }
+
+    #[test]
+    fn route() {
+        let directory = ViewerDirectory::default();
+        let alice = viewer("a1", Some("1"));
+        let (_, mut alice_tab) = directory.register(&alice);
+        let (_, mut alice_other_tab) = directory.register(&viewer("a2", Some("1")));
+        let (_, mut bob) = directory.register(&viewer("b1", None));
+
+        assert_eq!(directory.send("a1", &text("tab")), 1);
+        assert_eq!(directory.send("1", &text("user")), 2);
+        assert_eq!(directory.send("Uanon", &text("opaque")), 1);
+        assert_eq!(directory.send("nobody", &text("lost")), 0);
+
+        assert_eq!(alice_tab.try_recv().unwrap(), text("tab"));
+        assert_eq!(alice_tab.try_recv().unwrap(), text("user"));
+        assert_eq!(alice_other_tab.try_recv().unwrap(), text("user"));
+        assert_eq!(bob.try_recv().unwrap(), text("opaque"));
+
+        directory.unregister(&alice);
+        assert_eq!(directory.send("a1", &text("gone")), 0);
+    }
+}
@@ -0,0 +1,393 @@
+//! Forwarding between a connected viewer and the game
+
+use std::time::Instant;
+
+use rocket::futures::stream::SplitStream;
+use rocket::futures::{Sink, SinkExt, StreamExt};
+use rocket::serde::json::serde_json;
+use rocket::tokio::sync::{broadcast, mpsc, watch};
+use serde::{Deserialize, Serialize};
+use twitch_minimap_protocol::{CloseReason, Notice, Presence, Resync, ViewerError};
+use ws::stream::DuplexStream;
+
+use crate::auth::ViewerIdentity;
+use crate::backend::{LobbyLink, Route};
+use crate::encoding::{Encoding, SharedMessage};
+use crate::heartbeat::{Beat, Heartbeat, HeartbeatConfig, LastSeen};
+use crate::metrics::{Direction, DropReason, Metrics};
+use crate::protocol::Declaration;
+use crate::rate_limit::RateLimiter;
+use crate::sticky::{sticky_key, StickyBroadcast};
+use crate::targeted::ViewerDirectory;
+use crate::{close_frame, stamp_viewer_message};
+
+/// What to do when a viewer falls so far behind the game that messages were lost
+#[derive(FromFormField, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LagPolicy {
+    /// Skip everything the viewer missed, then send a resync marker and the sticky messages
+    #[default]
+    Resync,
+    /// Keep forwarding what is left, but drop messages a newer sticky message replaces
+    Coalesce,
+    /// Close the connection with a reason
+    Disconnect,
+}
+
+/// How to continue after a viewer fell behind
+#[derive(Debug, PartialEq)]
+enum CatchUp {
+    /// Send these messages, then continue as usual
+    Send(Vec<SharedMessage>),
+    /// Close the connection
+    Disconnect,
+}
+
+impl LagPolicy {
+    /// Decide how to continue after `from_game` lagged and `skipped` messages were lost
+    fn catch_up(
+        self,
+        skipped: u64,
+        from_game: &mut broadcast::Receiver<SharedMessage>,
+        broadcast: &StickyBroadcast,
+    ) -> CatchUp {
+        match self {
+            Self::Resync => {
+                let (latest, snapshot) = broadcast.subscribe();
+                let skipped = skipped + u64::try_from(from_game.len()).unwrap_or(u64::MAX);
+                *from_game = latest;
+
+                let marker = serde_json::to_string(&Notice::Resync(Resync { skipped }))
+                    .ok()
+                    .map(|marker| ws::Message::Text(marker).into());
+                CatchUp::Send(marker.into_iter().chain(snapshot).collect())
+            }
+            Self::Coalesce => {
+                let mut backlog = Vec::new();
+                loop {
+                    match from_game.try_recv() {
+                        Ok(message) => backlog.push(message),
+                        Err(broadcast::error::TryRecvError::Lagged(_)) => {}
+                        Err(_) => break,
+                    }
+                }
+
+                let keys = backlog
+                    .iter()
+                    .map(|message| sticky_key(message.original()))
+                    .collect::<Vec<_>>();
+                let messages = backlog
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(i, _)| {
+                        keys[i + 1..]
+                            .iter()
+                            .all(|later| later.is_none() || *later != keys[*i])
+                    })
+                    .map(|(_, message)| message)
+                    .collect();
+                CatchUp::Send(messages)
+            }
+            Self::Disconnect => CatchUp::Disconnect,
+        }
+    }
+}
+
+/// Queue the error frame for the viewer, dropping it if the viewer is not reading anyway
+fn reject(error: ViewerError, reply: &mpsc::Sender<ws::Message>) {
+    if let Ok(frame) = serde_json::to_string(&error) {
+        let _ = reply.try_send(ws::Message::Text(frame));
+    }
+}
+
+/// Everything a viewer connection needs from its lobby
+#[derive(Debug)]
+pub struct ViewerSession {
+    /// Who is connected
+    pub viewer: ViewerIdentity,
+    /// Messages to the game, wherever it is connected
+    pub to_game: LobbyLink,
+    /// Messages from the game
+    pub from_game: StickyBroadcast,
+    /// Where the game finds this viewer to send it messages only it gets
+    pub directory: ViewerDirectory,
+    /// What to do if the viewer falls behind
+    pub lag_policy: LagPolicy,
+    /// What the game speaks, sent to the viewer before anything else
+    pub protocol: Declaration,
+    /// What the viewer wants to receive
+    pub encoding: Encoding,
+    /// How fast the viewer may send messages
+    pub limiter: RateLimiter,
+    /// How to ping the viewer
+    pub heartbeat: HeartbeatConfig,
+    /// Reason the lobby was closed, once it is
+    pub closed: watch::Receiver<Option<String>>,
+    /// Metrics of the lobby
+    pub metrics: Metrics,
+}
+
+impl ViewerSession {
+    /// Forward messages in both directions until either side goes away
+    pub async fn run(self, connection: DuplexStream) -> ws::result::Result<()> {
+        let (mut connection_send, connection_recv) = connection.split();
+        if let Some(hello) = self.protocol.hello() {
+            connection_send.send(self.encoding.convert(hello)).await?;
+        }
+        let heartbeat = Heartbeat::new(self.heartbeat);
+        let (reply, addressed) = self.directory.register(&self.viewer);
+        let viewer = self.viewer.clone();
+        announce(&self.to_game, Presence::ViewerJoined { viewer }).await;
+        let res = rocket::tokio::select!(
+            res = forward_to_game(
+                connection_recv,
+                heartbeat.last_seen(),
+                &self.viewer,
+                &self.limiter,
+                &self.to_game,
+                reply,
+                self.metrics.clone(),
+            ) => res,
+            res = forward_to_viewer(&self, connection_send, addressed, heartbeat) => res,
+        );
+        self.directory.unregister(&self.viewer);
+        self.limiter.forget(&self.viewer);
+        let viewer = self.viewer;
+        announce(&self.to_game, Presence::ViewerLeft { viewer }).await;
+        res
+    }
+}
+
+/// Let the game know a viewer connected or disconnected
+async fn announce(to_game: &LobbyLink, presence: Presence) {
+    let Ok(message) = serde_json::to_string(&presence) else {
+        return;
+    };
+    to_game.send(Route::Game, ws::Message::Text(message)).await;
+}
+
+/// Forward the messages of the viewer to the game
+async fn forward_to_game(
+    mut connection_recv: SplitStream<DuplexStream>,
+    last_seen: LastSeen,
+    viewer: &ViewerIdentity,
+    limiter: &RateLimiter,
+    to_game: &LobbyLink,
+    reply: mpsc::Sender<ws::Message>,
+    metrics: Metrics,
+) -> ws::result::Result<()> {
+    // Only the first dropped message is answered, until the viewer gets a message through again
+    let mut throttled = false;
+
+    while let Some(Ok(message)) = connection_recv.next().await {
+        last_seen.seen();
+        if !(message.is_close() || message.is_ping() || message.is_pong()) {
+            if message.len() > limiter.max_size() {
+                log::warn!("Client sent message of length {}", message.len());
+                metrics.dropped(DropReason::TooLarge);
+                let max_size = limiter.max_size();
+                reject(ViewerError::MessageTooLarge { max_size }, &reply);
+                continue;
+            }
+
+            if let Err(retry_after) = limiter.check(viewer, Instant::now()) {
+                metrics.dropped(DropReason::RateLimited);
+                if !throttled {
+                    throttled = true;
+                    let retry_after_ms = u64::try_from(retry_after.as_millis()).unwrap_or(u64::MAX);
+                    reject(ViewerError::RateLimited { retry_after_ms }, &reply);
+                }
+                continue;
+            }
+            throttled = false;
+
+            let Some(message) = stamp_viewer_message(viewer, &message) else {
+                log::warn!("Client sent a message that can not be forwarded");
+                continue;
+            };
+            metrics.forwarded(Direction::ToGame, message.len());
+            to_game.send(Route::Game, message).await;
+        }
+    }
+    log::info!("CLIENT: Websocket closed!");
+    Ok(())
+}
+
+/// Forward the messages of the game to the viewer
+async fn forward_to_viewer(
+    session: &ViewerSession,
+    mut connection_send: impl Sink<ws::Message, Error = ws::result::Error> + Unpin,
+    mut addressed: mpsc::Receiver<ws::Message>,
+    mut heartbeat: Heartbeat,
+) -> ws::result::Result<()> {
+    let &ViewerSession {
+        ref from_game,
+        lag_policy,
+        encoding,
+        ref metrics,
+        ..
+    } = session;
+    let mut closed = session.closed.clone();
+    let (mut channel_recv, snapshot) = from_game.subscribe();
+
+    // Catch the viewer up before the live messages
+    for message in snapshot {
+        let message = message.get(encoding);
+        metrics.forwarded(Direction::ToViewer, message.len());
+        connection_send.send(message).await?;
+    }
+
+    loop {
+        rocket::tokio::select! {
+            res = channel_recv.recv() => {
+                // Every viewer shares the conversion, so it is done once per message
+                let message = match res {
+                    Ok(message) => message.get(encoding),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        log::info!("CLIENT: Fell behind by {skipped} messages");
+                        metrics.lagged();
+                        match lag_policy.catch_up(skipped, &mut channel_recv, from_game) {
+                            CatchUp::Send(messages) => {
+                                for message in messages {
+                                    let message = message.get(encoding);
+                                    metrics.forwarded(Direction::ToViewer, message.len());
+                                    connection_send.send(message).await?;
+                                }
+                                continue;
+                            }
+                            CatchUp::Disconnect => {
+                                let reason = "Viewer could not keep up with the game";
+                                let code = ws::frame::CloseCode::Away.into();
+                                connection_send.send(close_frame(code, reason.into())).await?;
+                                break;
+                            }
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        log::info!("CLIENT: Channel closed (stream disconnected)");
+                        break;
+                    }
+                };
+                metrics.forwarded(Direction::ToViewer, message.len());
+                connection_send.send(message).await?;
+            },
+            // Counted by the streamer, so replies of the server are not counted as forwarded
+            Some(message) = addressed.recv() => {
+                connection_send.send(encoding.convert(message)).await?;
+            },
+            beat = heartbeat.tick() => {
+                if beat == Beat::Dead {
+                    log::info!("CLIENT: Connection timed out");
+                    break;
+                }
+                connection_send.send(ws::Message::Ping(Vec::new())).await?;
+            },
+            _ = closed.changed() => {
+                log::info!("CLIENT: Lobby was closed");
+                let reason = closed.borrow().clone().unwrap_or_default();
+                let closed = close_frame(CloseReason::LobbyClosed.code(), reason);
+                connection_send.send(closed).await?;
+                break;
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+
+    fn text(text: &str) -> ws::Message {
+        ws::Message::Text(text.into())
+    }
+
+    /// A viewer that subscribed and then stopped reading while the game sent `messages`
+    fn stalled(messages: &[&str]) -> (StickyBroadcast, broadcast::Receiver<SharedMessage>, u64) {
+        let broadcast = StickyBroadcast::new(4);
+        let (mut viewer, _) = broadcast.subscribe();
+        for message in messages {
+            broadcast.send(text(message));
+        }
+        let skipped = match viewer.try_recv() {
+            Err(broadcast::error::TryRecvError::Lagged(skipped)) => skipped,
+            _ => 0,
+        };
+        (broadcast, viewer, skipped)
+    }
+
+    #[test]
+    fn rejected() {
+        let (reply, mut frames) = mpsc::channel(2);
+        reject(
+            ViewerError::RateLimited {
+                retry_after_ms: 1500,
+            },
+            &reply,
+        );
+        reject(ViewerError::MessageTooLarge { max_size: 1000 }, &reply);
+
+        assert_eq!(
+            frames.try_recv().unwrap(),
+            text(r#"{"error":"rate_limited","retry_after_ms":1500}"#)
+        );
+        assert_eq!(
+            frames.try_recv().unwrap(),
+            text(r#"{"error":"message_too_large","max_size":1000}"#)
+        );
+    }
+
+    #[test]
+    fn resync() {
+        let (broadcast, mut viewer, skipped) = stalled(&[
+            r#"{"data": {"css": "a"}}"#,
+            "1",
+            "2",
+            "3",
+            r#"{"data": []}"#,
+            "4",
+        ]);
+        assert_eq!(skipped, 2);
+
+        let catch_up = LagPolicy::Resync.catch_up(skipped, &mut viewer, &broadcast);
+        assert_eq!(
+            catch_up,
+            CatchUp::Send(vec![
+                text(r#"{"resync":{"skipped":6}}"#).into(),
+                text(r#"{"data": {"css": "a"}}"#).into(),
+                text(r#"{"data": []}"#).into(),
+            ])
+        );
+
+        broadcast.send(text("live"));
+        assert_eq!(viewer.try_recv().unwrap(), text("live"));
+    }
+
+    #[test]
+    fn coalesce() {
+        let (broadcast, mut viewer, skipped) =
+            stalled(&["0", r#"{"data": [1]}"#, "1", r#"{"data": [2]}"#, "2"]);
+        assert_eq!(skipped, 1);
+
+        let catch_up = LagPolicy::Coalesce.catch_up(skipped, &mut viewer, &broadcast);
+        assert_eq!(
+            catch_up,
+            CatchUp::Send(vec![
+                text("1").into(),
+                text(r#"{"data": [2]}"#).into(),
+                text("2").into()
+            ])
+        );
+    }
+
+    #[test]
+    fn disconnect() {
+        let (broadcast, mut viewer, skipped) = stalled(&["1", "2", "3", "4", "5"]);
+        assert_eq!(skipped, 1);
+
+        let catch_up = LagPolicy::Disconnect.catch_up(skipped, &mut viewer, &broadcast);
+        assert_eq!(catch_up, CatchUp::Disconnect);
+    }
+}
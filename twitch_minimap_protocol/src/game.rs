@@ -0,0 +1,136 @@
+//! What the game sends to the extensions.
+
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::viewer::Viewer;
+
+/// A unit shown on the minimap.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Unit {
+    /// Unique id of the unit, also added as the css class `_id` to it.
+    pub id: u32,
+    /// Css class added to the unit, to reuse css across units.
+    pub kind: String,
+    /// Horizontal position, from 0 on the left to 1 on the right.
+    pub x: f32,
+    /// Vertical position, from 0 at the top to 1 at the bottom.
+    pub y: f32,
+}
+
+/// Removes every unit from the minimap.
+///
+/// Written as `null`, but the value is ignored when reading so games that send `true` work too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Reset;
+
+impl Serialize for Reset {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_unit()
+    }
+}
+
+impl<'de> Deserialize<'de> for Reset {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        IgnoredAny::deserialize(deserializer).map(|_| Self)
+    }
+}
+
+/// The minimap data the game sends.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerData {
+    /// Css injected into the extension, replacing the previous css.
+    Css(String),
+    /// Clears the minimap.
+    Reset(Reset),
+    /// Every unit on the minimap.
+    #[serde(untagged)]
+    Units(Vec<Unit>),
+}
+
+/// A message from the game to every viewer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bevy", derive(bevy_ecs::event::Event))]
+pub struct ServerEvent {
+    /// The minimap data.
+    pub data: ServerData,
+}
+
+/// Data sent to a single viewer instead of everyone watching.
+///
+/// Unlike the minimap data this is not replayed to viewers who connect later.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bevy", derive(bevy_ecs::event::Event))]
+pub struct SendToViewer {
+    /// Connection id, opaque user id or user id of the viewer.
+    ///
+    /// A user id or opaque user id reaches every connection of that viewer.
+    pub to: String,
+    /// The data the viewer receives.
+    pub data: serde_json::Value,
+}
+
+impl SendToViewer {
+    /// Send `data` to the connection `viewer` sent a message from.
+    pub fn reply(viewer: &Viewer, data: serde_json::Value) -> Self {
+        Self {
+            to: viewer.connection_id.clone(),
+            data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::tests::assert_wire;
+
+    #[test]
+    fn units() {
+        let units = ServerEvent {
+            data: ServerData::Units(vec![Unit {
+                id: 1234,
+                kind: "Sphere".into(),
+                x: 0.5,
+                y: 0.25,
+            }]),
+        };
+        assert_wire(
+            &units,
+            r#"{"data": [{"id": 1234, "kind": "Sphere", "x": 0.5, "y": 0.25}]}"#,
+        );
+    }
+
+    #[test]
+    fn css() {
+        let css = ServerEvent {
+            data: ServerData::Css("._1 {color: red}".into()),
+        };
+        assert_wire(&css, r#"{"data": {"css": "._1 {color: red}"}}"#);
+    }
+
+    #[test]
+    fn reset() {
+        let reset = ServerEvent {
+            data: ServerData::Reset(Reset),
+        };
+        assert_wire(&reset, r#"{"data": {"reset": null}}"#);
+        // What the unity game sends
+        assert_eq!(
+            serde_json::from_str::<ServerEvent>(r#"{"data": {"reset": true}}"#).unwrap(),
+            reset
+        );
+    }
+
+    #[test]
+    fn send_to_viewer() {
+        let message = SendToViewer {
+            to: "4f1c0e5e".into(),
+            data: json!({"score": 3}),
+        };
+        assert_wire(&message, r#"{"to": "4f1c0e5e", "data": {"score": 3}}"#);
+    }
+}
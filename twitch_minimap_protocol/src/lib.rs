@@ -0,0 +1,47 @@
+//! Messages exchanged between games, the server and the twitch minimap extension.
+//!
+//! The types are grouped by who sends them:
+//!
+//! * [`game`]: what the game sends to the extensions.
+//! * [`viewer`]: who the viewers are and what their extensions send to the game.
+//! * [`server`]: what the server itself answers.
+//!
+//! [`protocol`] holds the version and features a game declares when creating its lobby, and
+//! [`url`] the paths of the server.
+//!
+//! Every message is json, or msgpack for connections that ask for it. The tests of each module
+//! pin the json the types read and write.
+#![warn(missing_docs)]
+
+pub mod game;
+pub mod protocol;
+pub mod server;
+pub mod url;
+pub mod viewer;
+
+pub use game::{Reset, SendToViewer, ServerData, ServerEvent, Unit};
+pub use protocol::{Feature, Protocol, UnknownVersion, Version};
+pub use server::{CloseReason, GameState, LobbyStatus, Notice, Resync, StreamerLogin, ViewerError};
+pub use viewer::{Click, ClientData, Presence, Role, Viewer, ViewerMessage};
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+
+    /// Check that `value` is written as `json`, and that both json and msgpack read back to it.
+    pub fn assert_wire<T>(value: &T, json: &str)
+    where
+        T: Serialize + DeserializeOwned + PartialEq + Debug,
+    {
+        let written: serde_json::Value = serde_json::to_value(value).unwrap();
+        let expected: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(written, expected);
+        assert_eq!(serde_json::from_str::<T>(json).unwrap(), *value);
+
+        let msgpack = rmp_serde::to_vec_named(value).unwrap();
+        assert_eq!(rmp_serde::from_slice::<T>(&msgpack).unwrap(), *value);
+    }
+}
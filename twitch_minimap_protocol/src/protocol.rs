@@ -0,0 +1,175 @@
+//! What a game declares about the messages it sends.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Version of the message format.
+///
+/// Written as a plain number, a new version is only added when a message changes in a way older
+/// extensions would misread.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+)]
+#[serde(try_from = "u32", into = "u32")]
+pub enum Version {
+    /// The messages described in this crate.
+    #[default]
+    V1 = 1,
+}
+
+impl Version {
+    /// The newest version.
+    pub const LATEST: Self = Self::V1;
+}
+
+impl From<Version> for u32 {
+    fn from(version: Version) -> Self {
+        version as u32
+    }
+}
+
+impl TryFrom<u32> for Version {
+    type Error = UnknownVersion;
+
+    fn try_from(version: u32) -> Result<Self, Self::Error> {
+        match version {
+            1 => Ok(Self::V1),
+            _ => Err(UnknownVersion(version)),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        u32::from(*self).fmt(f)
+    }
+}
+
+/// A version newer than this crate knows about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownVersion(pub u32);
+
+impl fmt::Display for UnknownVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown protocol version {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownVersion {}
+
+/// Optional messages a game sends.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Feature {
+    /// [`ServerData::Css`](crate::ServerData::Css).
+    Css,
+    /// [`ServerData::Reset`](crate::ServerData::Reset).
+    Reset,
+    /// [`SendToViewer`](crate::SendToViewer).
+    Targeted,
+    /// Anything this crate does not know about, kept so it can be passed on.
+    #[serde(untagged)]
+    Other(String),
+}
+
+impl Feature {
+    /// The name of the feature, as it is written.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Css => "css",
+            Self::Reset => "reset",
+            Self::Targeted => "targeted",
+            Self::Other(other) => other,
+        }
+    }
+}
+
+impl From<&str> for Feature {
+    fn from(feature: &str) -> Self {
+        match feature {
+            "css" => Self::Css,
+            "reset" => Self::Reset,
+            "targeted" => Self::Targeted,
+            other => Self::Other(other.to_owned()),
+        }
+    }
+}
+
+/// The message format a game speaks.
+///
+/// The game declares it when creating the lobby, extensions get it in
+/// [`Notice::Protocol`](crate::Notice::Protocol) right after connecting.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Protocol {
+    /// Version of the message format.
+    pub version: Version,
+    /// Optional messages the game sends.
+    #[serde(default)]
+    pub features: Vec<Feature>,
+}
+
+impl Protocol {
+    /// Whether the game said it sends `feature`.
+    pub fn supports(&self, feature: &Feature) -> bool {
+        self.features.contains(feature)
+    }
+
+    /// Query parameters declaring this protocol when creating a lobby.
+    pub fn query(&self) -> String {
+        let mut query = format!("protocol.version={}", self.version);
+        for feature in &self.features {
+            query.push_str("&protocol.features=");
+            query.push_str(feature.as_str());
+        }
+        query
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::assert_wire;
+
+    #[test]
+    fn version() {
+        assert_wire(&Version::V1, "1");
+        assert_eq!(Version::try_from(2), Err(UnknownVersion(2)));
+        assert!(serde_json::from_str::<Version>("2").is_err());
+        assert!(serde_json::from_str::<Version>("0").is_err());
+    }
+
+    #[test]
+    fn features() {
+        assert_wire(
+            &vec![
+                Feature::Css,
+                Feature::Reset,
+                Feature::Targeted,
+                Feature::Other("sound".into()),
+            ],
+            r#"["css", "reset", "targeted", "sound"]"#,
+        );
+        assert_eq!(Feature::from("targeted"), Feature::Targeted);
+        assert_eq!(Feature::from("sound").as_str(), "sound");
+    }
+
+    #[test]
+    fn protocol() {
+        let protocol = Protocol {
+            version: Version::V1,
+            features: vec![Feature::Css, Feature::Reset],
+        };
+        assert_wire(&protocol, r#"{"version": 1, "features": ["css", "reset"]}"#);
+        assert_eq!(
+            serde_json::from_str::<Protocol>(r#"{"version": 1}"#).unwrap(),
+            Protocol::default()
+        );
+        assert!(protocol.supports(&Feature::Css));
+        assert!(!protocol.supports(&Feature::Targeted));
+        assert_eq!(
+            protocol.query(),
+            "protocol.version=1&protocol.features=css&protocol.features=reset"
+        );
+    }
+}
@@ -0,0 +1,185 @@
+//! What the server itself answers.
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::Protocol;
+
+/// Sent by the server to an extension, next to the messages of the game.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Notice {
+    /// The protocol the game speaks, always the first message after connecting.
+    Protocol(Protocol),
+    /// The extension fell behind, the latest state of the game follows.
+    Resync(Resync),
+}
+
+/// Details of [`Notice::Resync`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resync {
+    /// Number of messages the extension missed.
+    pub skipped: u64,
+}
+
+/// Why the server dropped a message of an extension, only that extension gets this.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum ViewerError {
+    /// The extension sent too fast, messages are dropped until it slows down.
+    RateLimited {
+        /// How long until the next message is accepted.
+        retry_after_ms: u64,
+    },
+    /// The message was longer than the lobby allows.
+    MessageTooLarge {
+        /// Longest message in bytes the lobby accepts.
+        max_size: usize,
+    },
+}
+
+/// Response of the server when creating a lobby or rotating its key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StreamerLogin {
+    /// Key to resume and manage the lobby with.
+    pub key: String,
+    /// Url to connect to as the streamer.
+    pub url: String,
+}
+
+/// Whether the game is attached to its lobby.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GameState {
+    /// The game has not connected yet.
+    Waiting,
+    /// The game is connected.
+    Connected,
+    /// The game disconnected and may still resume.
+    Disconnected,
+}
+
+/// Public status of a lobby.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LobbyStatus {
+    /// Whether the game is attached.
+    pub game: GameState,
+    /// Number of connected viewers.
+    pub viewers: usize,
+    /// When the lobby was created, as a unix timestamp.
+    pub created_at: u64,
+    /// The protocol the game declared.
+    #[serde(default)]
+    pub protocol: Protocol,
+}
+
+/// Why the server closed a websocket, sent as the code of the close frame next to a reason for
+/// people.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The lobby was closed, by the streamer, an admin or because it was not used anymore.
+    LobbyClosed,
+    /// Another connection of the game took over the lobby.
+    TakenOver,
+    /// The key of the lobby was rotated, the game has to resume with the new one.
+    KeyRotated,
+}
+
+impl CloseReason {
+    /// Code of the close frame, `1001` (going away) for a closed lobby and one of the application
+    /// codes otherwise.
+    pub const fn code(self) -> u16 {
+        match self {
+            Self::LobbyClosed => 1001,
+            Self::TakenOver => 4001,
+            Self::KeyRotated => 4002,
+        }
+    }
+
+    /// Read the code of a close frame, `None` if the server does not close with it on purpose.
+    pub const fn from_code(code: u16) -> Option<Self> {
+        match code {
+            1001 => Some(Self::LobbyClosed),
+            4001 => Some(Self::TakenOver),
+            4002 => Some(Self::KeyRotated),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Feature, Version};
+    use crate::tests::assert_wire;
+
+    #[test]
+    fn notices() {
+        let protocol = Protocol {
+            version: Version::V1,
+            features: vec![Feature::Css],
+        };
+        assert_wire(
+            &Notice::Protocol(protocol),
+            r#"{"protocol": {"version": 1, "features": ["css"]}}"#,
+        );
+        assert_wire(
+            &Notice::Resync(Resync { skipped: 12 }),
+            r#"{"resync": {"skipped": 12}}"#,
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_wire(
+            &ViewerError::RateLimited {
+                retry_after_ms: 1500,
+            },
+            r#"{"error": "rate_limited", "retry_after_ms": 1500}"#,
+        );
+        assert_wire(
+            &ViewerError::MessageTooLarge { max_size: 1000 },
+            r#"{"error": "message_too_large", "max_size": 1000}"#,
+        );
+    }
+
+    #[test]
+    fn login() {
+        assert_wire(
+            &StreamerLogin {
+                key: "your_key".into(),
+                url: "wss://example.com/lobby/connect/streamer?user=123&key=your_key".into(),
+            },
+            r#"{"key": "your_key",
+                "url": "wss://example.com/lobby/connect/streamer?user=123&key=your_key"}"#,
+        );
+    }
+
+    #[test]
+    fn close_codes() {
+        for reason in [
+            CloseReason::LobbyClosed,
+            CloseReason::TakenOver,
+            CloseReason::KeyRotated,
+        ] {
+            assert_eq!(CloseReason::from_code(reason.code()), Some(reason));
+        }
+        assert_eq!(CloseReason::LobbyClosed.code(), 1001);
+        assert_eq!(CloseReason::from_code(1000), None);
+        assert_eq!(CloseReason::from_code(1002), None);
+    }
+
+    #[test]
+    fn status() {
+        assert_wire(
+            &LobbyStatus {
+                game: GameState::Disconnected,
+                viewers: 3,
+                created_at: 1_728_000_000,
+                protocol: Protocol::default(),
+            },
+            r#"{"game": "disconnected", "viewers": 3, "createdAt": 1728000000,
+                "protocol": {"version": 1, "features": []}}"#,
+        );
+    }
+}
@@ -0,0 +1,61 @@
+//! Paths of the server, with their query.
+//!
+//! These start with a `/`, put the address of the server in front, `https://` for the plain
+//! requests and `wss://` for the websockets. User ids, keys and tokens are url safe as they are.
+
+use crate::protocol::Protocol;
+
+/// `POST` here to create the lobby of `user`, declaring the `protocol` the game speaks.
+///
+/// Answered with a [`StreamerLogin`](crate::StreamerLogin).
+pub fn new_lobby(user: &str, protocol: &Protocol) -> String {
+    format!("/lobby/new?user={user}&{}", protocol.query())
+}
+
+/// Websocket the game of `user` connects to with the `key` of its lobby.
+pub fn connect_streamer(user: &str, key: &str) -> String {
+    format!("/lobby/connect/streamer?user={user}&key={key}")
+}
+
+/// Websocket the extension connects to, with the extension `token` twitch issued for `user`.
+pub fn connect_viewer(user: &str, token: &str) -> String {
+    format!("/lobby/connect?user={user}&token={token}")
+}
+
+/// `DELETE` the lobby of `user`, with the `key` of the lobby.
+pub fn close(user: &str, key: &str) -> String {
+    format!("/lobby?user={user}&key={key}")
+}
+
+/// `GET` the [`LobbyStatus`](crate::LobbyStatus) of the lobby of `user`.
+pub fn status(user: &str) -> String {
+    format!("/lobby/status?user={user}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Feature;
+
+    #[test]
+    fn paths() {
+        let protocol = Protocol {
+            features: vec![Feature::Css],
+            ..Protocol::default()
+        };
+        assert_eq!(
+            new_lobby("123", &protocol),
+            "/lobby/new?user=123&protocol.version=1&protocol.features=css"
+        );
+        assert_eq!(
+            connect_streamer("123", "abc"),
+            "/lobby/connect/streamer?user=123&key=abc"
+        );
+        assert_eq!(
+            connect_viewer("123", "jwt"),
+            "/lobby/connect?user=123&token=jwt"
+        );
+        assert_eq!(close("123", "abc"), "/lobby?user=123&key=abc");
+        assert_eq!(status("123"), "/lobby/status?user=123");
+    }
+}
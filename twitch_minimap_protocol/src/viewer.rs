@@ -0,0 +1,168 @@
+//! Who the viewers are and what their extensions send to the game.
+
+use serde::{Deserialize, Serialize};
+
+/// The role a viewer has in the channel.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// The owner of the channel.
+    Broadcaster,
+    /// A moderator of the channel.
+    Moderator,
+    /// Any other logged in or anonymous viewer.
+    Viewer,
+    /// The token was created by an extension backend, not a viewer.
+    External,
+}
+
+/// A connected viewer.
+///
+/// This is filled in by the server from the viewers twitch token, so unlike anything else in a
+/// viewer message it can be trusted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct Viewer {
+    /// Opaque id of the viewer, this is always present but changes if the viewer logs out.
+    pub opaque_user_id: String,
+    /// Twitch id of the viewer, only present if they shared their identity with the extension.
+    pub user_id: Option<String>,
+    /// Role of the viewer in the channel.
+    pub role: Role,
+    /// Unique id of the viewers connection, a viewer with multiple tabs open has multiple.
+    pub connection_id: String,
+}
+
+/// A click on the minimap.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Click {
+    /// Horizontal position, from 0 on the left to 1 on the right.
+    pub x: f32,
+    /// Vertical position, from 0 at the top to 1 at the bottom.
+    pub y: f32,
+    /// Color the viewer picked.
+    pub bubble_color: String,
+    /// Size the viewer picked.
+    pub bubble_size: f32,
+    /// Item the viewer picked, `Random`, `Sphere` or `Cube`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub item_type: Option<String>,
+    /// Twitch id of the viewer as claimed by the extension, prefer [`ViewerMessage::viewer`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+/// What the extension sends.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ClientData {
+    /// The viewer clicked on the minimap.
+    Click(Click),
+}
+
+/// A message of a viewer, as the server forwards it to the game.
+///
+/// `T` is what the extension sent, anything that is not json is forwarded as a string.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ViewerMessage<T = ClientData> {
+    /// Who sent the message.
+    pub viewer: Viewer,
+    /// What the extension sent.
+    pub data: T,
+}
+
+/// Tells the game that a viewer connected or disconnected, sent by the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Presence {
+    /// The viewer connected.
+    ViewerJoined {
+        /// Who connected.
+        viewer: Viewer,
+    },
+    /// The viewer disconnected.
+    ViewerLeft {
+        /// Who disconnected, `connection_id` matches the one they joined with.
+        viewer: Viewer,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::tests::assert_wire;
+
+    fn viewer() -> Viewer {
+        Viewer {
+            opaque_user_id: "U12345".into(),
+            user_id: Some("12345".into()),
+            role: Role::Moderator,
+            connection_id: "4f1c0e5e".into(),
+        }
+    }
+
+    const VIEWER: &str = r#"{
+        "opaqueUserId": "U12345",
+        "userId": "12345",
+        "role": "moderator",
+        "connectionId": "4f1c0e5e"
+    }"#;
+
+    #[test]
+    fn click() {
+        let click = ViewerMessage {
+            viewer: viewer(),
+            data: ClientData::Click(Click {
+                x: 0.5,
+                y: 0.25,
+                bubble_color: "#00ff00".into(),
+                bubble_size: 50.0,
+                item_type: Some("Cube".into()),
+                user_id: Some("12345".into()),
+            }),
+        };
+        assert_wire(
+            &click,
+            &format!(
+                r##"{{"viewer": {VIEWER}, "data": {{
+                    "x": 0.5, "y": 0.25, "bubbleColor": "#00ff00", "bubbleSize": 50.0,
+                    "itemType": "Cube", "userId": "12345"
+                }}}}"##
+            ),
+        );
+    }
+
+    #[test]
+    fn anything_else() {
+        let message = ViewerMessage {
+            viewer: Viewer {
+                user_id: None,
+                role: Role::Viewer,
+                ..viewer()
+            },
+            data: Value::from("Hello Server!"),
+        };
+        assert_wire(
+            &message,
+            r#"{"viewer": {"opaqueUserId": "U12345", "userId": null, "role": "viewer",
+                "connectionId": "4f1c0e5e"}, "data": "Hello Server!"}"#,
+        );
+        let vote = json!({"viewer": serde_json::from_str::<Value>(VIEWER).unwrap(), "data": {}});
+        assert!(serde_json::from_value::<ViewerMessage>(vote).is_err());
+    }
+
+    #[test]
+    fn presence() {
+        assert_wire(
+            &Presence::ViewerJoined { viewer: viewer() },
+            &format!(r#"{{"event": "viewer_joined", "viewer": {VIEWER}}}"#),
+        );
+        assert_wire(
+            &Presence::ViewerLeft { viewer: viewer() },
+            &format!(r#"{{"event": "viewer_left", "viewer": {VIEWER}}}"#),
+        );
+    }
+}